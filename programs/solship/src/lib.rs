@@ -1,41 +1,289 @@
-use anchor_lang::{prelude::*, solana_program};
+use anchor_lang::{prelude::*, solana_program, system_program};
+use anchor_spl::{
+    associated_token::AssociatedToken,
+    token::{self, Mint, Token, TokenAccount},
+};
 use solana_program::blake3::{hash, Hash};
 
 declare_id!("8ud2dBF8N4f9eZwiWnYZ3TEXEaEvm4QHr6Tu6tYKkJ5T");
 
 type BoardHash = [u8; 32];
 
-const TURN_DURATION: u8 = 75; // 75 slots = 75 * 0.4s = 30s
+const REVEAL_DURATION: u8 = 150; // 150 slots = 150 * 0.4s = 60s
+/// Leaf indices are serialized as a single byte, so a board has at most 16 * 16 = 256 fields.
+const MAX_BOARD_SIDE: u8 = 16;
+const MAX_BOARD_FIELDS: usize = 256;
+const MAX_FLEET_SIZE: usize = 10;
+const MAX_SHOTS_PER_TURN: u8 = MAX_FLEET_SIZE as u8;
+/// Ship id of water fields, ship fields use the index of their ship in `RuleSet::fleet`.
+const NO_SHIP: u8 = u8::MAX;
+/// Maximum number of players waiting in one queue page. Pages are indexed by a `u16`,
+/// so the whole queue holds at most 65536 * 32 = 2,097,152 players.
+const QUEUE_PAGE_CAPACITY: usize = 32;
+/// Bounds of `Queue::max_entry_age_slots`, about a minute to a day of 400 ms slots. Anyone can
+/// create a page, so its entries can neither expire right away nor stay in it forever.
+const MIN_QUEUE_ENTRY_AGE_SLOTS: u64 = 150;
+const MAX_QUEUE_ENTRY_AGE_SLOTS: u64 = 216_000;
+/// Protocol fees are expressed in basis points of the pot, 10,000 bps = 100%.
+const BPS_DENOMINATOR: u64 = 10_000;
+/// Highest fee the admin can set, 1,000 bps = 10% of the pot.
+const MAX_FEE_BPS: u16 = 1_000;
+/// Longest turn the admin can allow, about a week of 400 ms slots. Keeps the turn deadline far
+/// away from `u64::MAX`.
+const MAX_TURN_DURATION_SLOTS: u64 = 1_512_000;
+/// Actions a session key may take on behalf of its player, combined in `SessionScope::permissions`.
+const SESSION_ATTACK: u8 = 1 << 0;
+const SESSION_PROVE: u8 = 1 << 1;
+const SESSION_CLAIM: u8 = 1 << 2;
+const SESSION_RESIGN: u8 = 1 << 3;
+const SESSION_ALL: u8 = SESSION_ATTACK | SESSION_PROVE | SESSION_CLAIM | SESSION_RESIGN;
 
 #[program]
 pub mod solship {
     use super::*;
 
-    pub fn initialize_queue(ctx: Context<InitializeQueue>) -> Result<()> {
+    pub fn initialize_queue(
+        ctx: Context<InitializeQueue>,
+        page: u16,
+        max_entry_age_slots: u64,
+    ) -> Result<()> {
+        check_queue_entry_age(max_entry_age_slots)?;
+
         let queue = &mut ctx.accounts.queue;
+        queue.page = page;
         queue.players = Vec::new();
+        queue.max_entry_age_slots = max_entry_age_slots;
         Ok(())
     }
 
-    pub fn join_queue(ctx: Context<JoinQueue>, board_root: BoardHash) -> Result<()> {
-        let queue = &mut ctx.accounts.queue;
+    pub fn initialize_game_counter(ctx: Context<InitializeGameCounter>) -> Result<()> {
+        ctx.accounts.game_counter.next_game_id = 0;
+        Ok(())
+    }
+
+    /// Creates the program config. Only the program's upgrade authority may call it and becomes
+    /// the config's admin.
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        fee_bps: u16,
+        treasury: Pubkey,
+        min_turn_duration_slots: u64,
+        max_turn_duration_slots: u64,
+    ) -> Result<()> {
+        check_fee(fee_bps)?;
+        check_turn_duration_bounds(min_turn_duration_slots, max_turn_duration_slots)?;
+
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.fee_bps = fee_bps;
+        config.treasury = treasury;
+        config.min_turn_duration_slots = min_turn_duration_slots;
+        config.max_turn_duration_slots = max_turn_duration_slots;
+        emit_config_updated(config);
+        Ok(())
+    }
+
+    pub fn update_fee(ctx: Context<UpdateConfig>, fee_bps: u16) -> Result<()> {
+        check_fee(fee_bps)?;
+
+        let config = &mut ctx.accounts.config;
+        config.fee_bps = fee_bps;
+        emit_config_updated(config);
+        Ok(())
+    }
+
+    pub fn update_treasury(ctx: Context<UpdateConfig>, treasury: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.treasury = treasury;
+        emit_config_updated(config);
+        Ok(())
+    }
+
+    /// Only affects players joining the queue afterwards, running games keep their clock.
+    pub fn update_turn_duration_bounds(
+        ctx: Context<UpdateConfig>,
+        min_turn_duration_slots: u64,
+        max_turn_duration_slots: u64,
+    ) -> Result<()> {
+        check_turn_duration_bounds(min_turn_duration_slots, max_turn_duration_slots)?;
+
+        let config = &mut ctx.accounts.config;
+        config.min_turn_duration_slots = min_turn_duration_slots;
+        config.max_turn_duration_slots = max_turn_duration_slots;
+        emit_config_updated(config);
+        Ok(())
+    }
+
+    /// Hands the config over to `new_admin`, the current admin loses all rights.
+    pub fn update_admin(ctx: Context<UpdateConfig>, new_admin: Pubkey) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = new_admin;
+        emit_config_updated(config);
+        Ok(())
+    }
+
+    /// Enqueues the player and escrows `settings.wager_amount` in their queue ticket until
+    /// they are matched or leave the queue. The stake is in lamports, or in `wager_mint` tokens
+    /// when the token accounts are passed. The player is only matched with players who chose
+    /// the same settings and stake. `session_scope` limits what the session key may do in the game.
+    pub fn join_queue(
+        ctx: Context<JoinQueue>,
+        page: u16,
+        board_root: BoardHash,
+        settings: GameSettings,
+        session_scope: SessionScope,
+    ) -> Result<()> {
         let player = *ctx.accounts.player.key;
+        let current_slot = Clock::get()?.slot;
+        let ticket = &ctx.accounts.queue_ticket;
+        let wager_amount = settings.wager_amount;
+
+        settings.rule_set.validate()?;
+        session_scope.validate()?;
+
+        let config = &ctx.accounts.config;
+        if settings.turn_duration_slots < config.min_turn_duration_slots
+            || settings.turn_duration_slots > config.max_turn_duration_slots
+        {
+            return err!(CustomError::TurnDurationOutOfBounds);
+        }
+
+        // The ticket spans all pages, so a player can wait in only one of them at a time
+        if ticket.in_queue && current_slot <= ticket.expires_slot {
+            return err!(CustomError::AlreadyInQueue);
+        }
+
+        // Return the lamports of a previous entry that expired without being matched,
+        // token stakes need the token accounts of `leave_queue` to be returned
+        if ticket.wager_mint.is_some() && ticket.wager_amount > 0 {
+            return err!(CustomError::StakeNotWithdrawn);
+        }
+        move_lamports(
+            &ticket.to_account_info(),
+            &ctx.accounts.player.to_account_info(),
+            ticket.wager_amount,
+        )?;
+
+        let wager_mint = ctx.accounts.wager_mint.as_ref().map(|mint| mint.key());
+        match wager_mint {
+            None => system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.player.to_account_info(),
+                        to: ctx.accounts.queue_ticket.to_account_info(),
+                    },
+                ),
+                wager_amount,
+            )?,
+            Some(_) => transfer_tokens(
+                required(&ctx.accounts.token_program)?,
+                required(&ctx.accounts.player_token_account)?,
+                required(&ctx.accounts.ticket_token_account)?,
+                &ctx.accounts.player.to_account_info(),
+                &[],
+                wager_amount,
+            )?,
+        }
+
+        let queue = &mut ctx.accounts.queue;
+        let ticket = &mut ctx.accounts.queue_ticket;
+
+        // Expired entries can never be matched, drop them before checking for free space
+        let max_entry_age_slots = queue.max_entry_age_slots;
+        queue
+            .players
+            .retain(|p| !is_queue_entry_expired(p, max_entry_age_slots, current_slot));
+
+        if queue.players.len() >= QUEUE_PAGE_CAPACITY {
+            return err!(CustomError::QueueFull);
+        }
 
         let game_player = GamePlayer {
             address: player,
             session_key: *ctx.accounts.session_key.key,
+            session_scope,
             board_root,
+            enqueued_slot: current_slot,
+            wager_mint,
+            settings,
         };
 
         queue.players.push(game_player);
+
+        ticket.bump = ctx.bumps.queue_ticket;
+        ticket.page = page;
+        ticket.in_queue = true;
+        ticket.expires_slot = current_slot.saturating_add(max_entry_age_slots);
+        ticket.wager_amount = wager_amount;
+        ticket.wager_mint = wager_mint;
+        Ok(())
+    }
+
+    pub fn leave_queue(ctx: Context<LeaveQueue>, _page: u16) -> Result<()> {
+        let queue = &mut ctx.accounts.queue;
+        let player = *ctx.accounts.player.key;
+
+        let ticket = &mut ctx.accounts.queue_ticket;
+
+        // The entry may already have been pruned, the ticket still holds the stake in that case
+        match queue.players.iter().position(|p| p.address == player) {
+            Some(pos) => {
+                queue.players.remove(pos);
+            }
+            None if !ticket.in_queue => return err!(CustomError::PlayerNotInQueue),
+            None => {}
+        }
+
+        match ticket.wager_mint {
+            None => move_lamports(
+                &ticket.to_account_info(),
+                &ctx.accounts.player.to_account_info(),
+                ticket.wager_amount,
+            )?,
+            Some(wager_mint) => {
+                let ticket_token_account = required(&ctx.accounts.ticket_token_account)?;
+                if ticket_token_account.mint != wager_mint {
+                    return err!(CustomError::WagerMintMismatch);
+                }
+                transfer_tokens(
+                    required(&ctx.accounts.token_program)?,
+                    ticket_token_account,
+                    required(&ctx.accounts.player_token_account)?,
+                    &ticket.to_account_info(),
+                    &[&[b"queue_ticket", player.as_ref(), &[ticket.bump]]],
+                    ticket.wager_amount,
+                )?
+            }
+        }
+        ticket.wager_amount = 0;
+        ticket.wager_mint = None;
+        ticket.in_queue = false;
+        Ok(())
+    }
+
+    /// Removes every queue entry older than `Queue::max_entry_age_slots`. Callable by anyone.
+    pub fn prune_queue(ctx: Context<PruneQueue>, _page: u16) -> Result<()> {
+        let queue = &mut ctx.accounts.queue;
+        let current_slot = Clock::get()?.slot;
+        let max_entry_age_slots = queue.max_entry_age_slots;
+
+        queue
+            .players
+            .retain(|p| !is_queue_entry_expired(p, max_entry_age_slots, current_slot));
         Ok(())
     }
 
     pub fn create_game(
         ctx: Context<CreateGame>,
+        queue_page: u16,
         enemy: Pubkey,
         board_root: BoardHash,
+        settings: GameSettings,
+        session_scope: SessionScope,
     ) -> Result<()> {
+        session_scope.validate()?;
+
         let player1_board_root = board_root;
         let pos = ctx
             .accounts
@@ -51,7 +299,106 @@ pub mod solship {
         let enemy_game_player = ctx.accounts.queue.players.remove(pos.unwrap());
         let enemy_board_root = enemy_game_player.board_root;
 
+        if is_queue_entry_expired(
+            &enemy_game_player,
+            ctx.accounts.queue.max_entry_age_slots,
+            Clock::get()?.slot,
+        ) {
+            return err!(CustomError::QueueEntryExpired);
+        }
+
+        // The entry only counts while the enemy's ticket still escrows their stake for this page
+        let enemy_ticket = &ctx.accounts.enemy_queue_ticket;
+        if !enemy_ticket.in_queue || enemy_ticket.page != queue_page {
+            return err!(CustomError::PlayerNotInQueue);
+        }
+
+        let wager_mint = ctx.accounts.wager_mint.as_ref().map(|mint| mint.key());
+        let enemy_settings = &enemy_game_player.settings;
+        if enemy_settings.wager_amount != settings.wager_amount {
+            return err!(CustomError::WagerMismatch);
+        }
+        if enemy_game_player.wager_mint != wager_mint {
+            return err!(CustomError::WagerMintMismatch);
+        }
+        let enemy_ticket = &ctx.accounts.enemy_queue_ticket;
+        if enemy_ticket.wager_amount != settings.wager_amount
+            || enemy_ticket.wager_mint != wager_mint
+        {
+            return err!(CustomError::InsufficientEscrow);
+        }
+        // The enemy's settings were validated against the config when they joined
+        if enemy_settings.turn_duration_slots != settings.turn_duration_slots {
+            return err!(CustomError::TurnDurationMismatch);
+        }
+        if enemy_settings.time_bank_slots != settings.time_bank_slots {
+            return err!(CustomError::TimeBankMismatch);
+        }
+        if enemy_settings.rule_set != settings.rule_set {
+            return err!(CustomError::RuleSetMismatch);
+        }
+        let GameSettings {
+            wager_amount,
+            turn_duration_slots,
+            time_bank_slots,
+            rule_set,
+        } = settings;
+
+        // Both stakes end up in the game's vault, the enemy's one comes out of their queue ticket
+        let enemy_ticket = &ctx.accounts.enemy_queue_ticket;
+        match wager_mint {
+            None => {
+                move_lamports(
+                    &enemy_ticket.to_account_info(),
+                    &ctx.accounts.vault.to_account_info(),
+                    wager_amount,
+                )?;
+                system_program::transfer(
+                    CpiContext::new(
+                        ctx.accounts.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: ctx.accounts.player.to_account_info(),
+                            to: ctx.accounts.vault.to_account_info(),
+                        },
+                    ),
+                    wager_amount,
+                )?;
+            }
+            Some(_) => {
+                let token_program = required(&ctx.accounts.token_program)?;
+                let game_token_vault = required(&ctx.accounts.game_token_vault)?;
+                transfer_tokens(
+                    token_program,
+                    required(&ctx.accounts.enemy_ticket_token_account)?,
+                    game_token_vault,
+                    &enemy_ticket.to_account_info(),
+                    &[&[b"queue_ticket", enemy.as_ref(), &[enemy_ticket.bump]]],
+                    wager_amount,
+                )?;
+                transfer_tokens(
+                    token_program,
+                    required(&ctx.accounts.player_token_account)?,
+                    game_token_vault,
+                    &ctx.accounts.player.to_account_info(),
+                    &[],
+                    wager_amount,
+                )?;
+            }
+        }
+
+        let enemy_ticket = &mut ctx.accounts.enemy_queue_ticket;
+        enemy_ticket.wager_amount = 0;
+        enemy_ticket.wager_mint = None;
+        enemy_ticket.in_queue = false;
+        ctx.accounts.vault.game = ctx.accounts.game.key();
+
+        let game_counter = &mut ctx.accounts.game_counter;
+        let game_id = game_counter.next_game_id;
+        game_counter.next_game_id += 1;
+
         let game = &mut ctx.accounts.game;
+        game.bump = ctx.bumps.game;
+        game.game_id = game_id;
         game.player1 = *ctx.accounts.player.key;
         game.player2 = enemy;
         game.player1_board_hash = player1_board_root;
@@ -59,21 +406,51 @@ pub mod solship {
         game.current_turn = 1;
         game.player1_session_key = *ctx.accounts.session_key.key;
         game.player2_session_key = enemy_game_player.session_key;
-        // game.player1_attacked_fields = [false; 100];
-        // game.player2_attacked_fields = [false; 100];
+        game.player1_session_scope = session_scope;
+        game.player2_session_scope = enemy_game_player.session_scope;
+        game.player1_attacked_fields = FieldBitboard::default();
+        game.player2_attacked_fields = FieldBitboard::default();
+        game.player1_answers = Vec::new();
+        game.player2_answers = Vec::new();
+        game.status = GameStatus::AwaitingAttacks;
         game.player1_attacked_this_turn = false;
         game.player2_attacked_this_turn = false;
-        game.player1_tried_verifing_this_turn = false;
-        game.player2_tried_verifing_this_turn = false;
-        game.field_player1_attacked_this_turn = 255;
-        game.field_player2_attacked_this_turn = 255;
-        game.player1_remaining_ship_fields = 17;
-        game.player2_remaining_ship_fields = 17;
+        game.player1_verified_proof_this_turn = false;
+        game.player2_verified_proof_this_turn = false;
+        game.player1_shots = Vec::new();
+        game.player2_shots = Vec::new();
+        // The creator opens an alternating game
+        game.side_to_move = match rule_set.turn_mode {
+            TurnMode::Simultaneous => Pubkey::default(),
+            TurnMode::Alternating => game.player1,
+        };
+        game.player1_remaining_ship_fields = rule_set.ship_fields();
+        game.player2_remaining_ship_fields = rule_set.ship_fields();
+        game.player1_ship_hits = vec![0; rule_set.fleet.len()];
+        game.player2_ship_hits = vec![0; rule_set.fleet.len()];
+        game.rule_set = rule_set;
+        skip_idle_moves(game);
+        game.turn_duration_slots = turn_duration_slots;
         game.turn_start_slot = Clock::get()?.slot;
+        game.time_bank_slots = time_bank_slots;
+        game.player1_time_bank_slots = time_bank_slots;
+        game.player2_time_bank_slots = time_bank_slots;
+        game.player1_clock_started_slot = game.turn_start_slot;
+        game.player2_clock_started_slot = game.turn_start_slot;
         game.winner = Pubkey::default();
+        game.revealer = Pubkey::default();
+        game.reveal_deadline_slot = 0;
+        game.loser_revealed_board = false;
+        game.wager_amount = wager_amount;
+        game.wager_mint = wager_mint;
+        game.fee_bps = ctx.accounts.config.fee_bps;
+        game.treasury = ctx.accounts.config.treasury;
 
         emit!(GameStarted {
             game: game.key(),
+            game_id,
+            wager_amount,
+            wager_mint,
             player1: game.player1,
             player2: game.player2
         });
@@ -81,242 +458,888 @@ pub mod solship {
         Ok(())
     }
 
-    pub fn attack(ctx: Context<VerifyProof>, field_to_attack: u8) -> Result<()> {
+    /// Fires the whole salvo of this turn, one field per shot.
+    pub fn attack(ctx: Context<VerifyProof>, fields_to_attack: Vec<u8>) -> Result<()> {
         let game = &mut ctx.accounts.game;
 
         let player = *ctx.accounts.player.key;
+        check_status(game, GameStatus::AwaitingAttacks)?;
+        check_fleets_afloat(game)?;
         check_time_expired(game)?;
-        let player = check_if_player_is_part_of_game(player, game)?;
+        let player = check_if_player_is_part_of_game(player, game, SESSION_ATTACK)?;
 
         if player != game.player1 && player != game.player2 {
             return Err(CustomError::PlayerNotPartOfGame.into());
         }
 
+        if game.rule_set.turn_mode == TurnMode::Alternating && player != game.side_to_move {
+            return err!(CustomError::NotYourTurn);
+        }
+
+        if fields_to_attack.len() != game.shots_this_turn(player) {
+            return err!(CustomError::WrongShotCount);
+        }
+
+        if fields_to_attack
+            .iter()
+            .any(|&field| field as usize >= game.rule_set.board_fields())
+        {
+            return err!(CustomError::FieldOutOfRange);
+        }
+
+        let current_slot = Clock::get()?.slot;
+        charge_time_bank(game, player, current_slot)?;
+
+        // Marking rejects fields attacked in an earlier turn as well as repeats within the salvo
         if player == game.player1 && !game.player1_attacked_this_turn {
-            // game.player1_attacked_fields[field_to_attack as usize] = true;
-            game.field_player1_attacked_this_turn = field_to_attack;
+            for &field in &fields_to_attack {
+                game.player1_attacked_fields.mark(field)?;
+            }
+            game.player1_shots = fields_to_attack;
             game.player1_attacked_this_turn = true;
         } else if player == game.player2 && !game.player2_attacked_this_turn {
-            // game.player2_attacked_fields[field_to_attack as usize] = true;
-            game.field_player2_attacked_this_turn = field_to_attack;
+            for &field in &fields_to_attack {
+                game.player2_attacked_fields.mark(field)?;
+            }
+            game.player2_shots = fields_to_attack;
             game.player2_attacked_this_turn = true;
         } else {
             return err!(CustomError::AlreadyAttackedThisTurn);
         }
 
-        emit!(FieldAttacked {
-            game: game.key(),
-            player,
-            attacked_field: field_to_attack
-        });
+        if game.player1_attacked_this_turn && game.player2_attacked_this_turn {
+            game.status = GameStatus::AwaitingProofs;
+            // The first attacker was waiting for the second one, that is not their time
+            game.player1_clock_started_slot = current_slot;
+            game.player2_clock_started_slot = game.player1_clock_started_slot;
+        }
+
+        let shots = if player == game.player1 {
+            &game.player1_shots
+        } else {
+            &game.player2_shots
+        };
+        for &attacked_field in shots {
+            emit!(FieldAttacked {
+                game: game.key(),
+                player,
+                attacked_field
+            });
+        }
 
         Ok(())
     }
 
-    pub fn verify_proof(
-        ctx: Context<VerifyProof>,
-        proof: [BoardHash; 7],
-        leaf: GameField,
-    ) -> Result<()> {
+    /// Answers any number of the enemy's shots of this turn. The player's part of the turn is
+    /// done once every shot is answered, which may take several transactions for large salvos.
+    pub fn verify_proof(ctx: Context<VerifyProof>, proofs: Vec<FieldProof>) -> Result<()> {
         // let player = *ctx.accounts.player.key;
         // let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
         // check_time_expired(game)?;
         // check_if_player_is_part_of_game(player, game)?;
 
-        // let proving_field_index = leaf.index;
+        check_status(&ctx.accounts.game, GameStatus::AwaitingProofs)?;
 
-        // Double hash the leaf to prevent second preimage attack "https://www.rareskills.io/post/merkle-tree-second-preimage-attack"
-        let hashed_leaf = hash_leaf(&leaf);
+        let player = check_if_player_is_part_of_game(
+            *ctx.accounts.player.key,
+            &ctx.accounts.game,
+            SESSION_PROVE,
+        )?;
+        let (root, player) = get_player_board_hash(player, &ctx.accounts.game)?;
 
-        let (root, player) =
-            get_player_board_hash(*ctx.accounts.player.key, &mut ctx.accounts.game)?;
+        // Only the defender answers in alternating mode
+        let game = &ctx.accounts.game;
+        if game.rule_set.turn_mode == TurnMode::Alternating && player == game.side_to_move {
+            return err!(CustomError::NotYourTurn);
+        }
 
-        let is_proof_valid = verify_merkle_proof(
-            hashed_leaf,
-            proof,
-            root,
-            leaf.index,
-            &mut ctx.accounts.game,
-            player,
-        )?;
+        let mut hits = 0;
+        for FieldProof { proof, leaf } in proofs {
+            // Double hash the leaf to prevent second preimage attack "https://www.rareskills.io/post/merkle-tree-second-preimage-attack"
+            let hashed_leaf = hash_leaf(&leaf);
+
+            let is_proof_valid = verify_merkle_proof(
+                hashed_leaf,
+                proof,
+                root,
+                leaf.index,
+                &ctx.accounts.game,
+                player,
+            )?;
+
+            if !is_proof_valid {
+                // ctx.accounts.game.winner = *ctx.accounts.player.key;
+                return err!(CustomError::InvalidProof);
+            }
 
-        if !is_proof_valid {
-            // ctx.accounts.game.winner = *ctx.accounts.player.key;
-            return err!(CustomError::InvalidProof);
+            let sunk_ship_length = record_hit(&mut ctx.accounts.game, player, &leaf)?;
+            record_answer(&mut ctx.accounts.game, player, &leaf);
+            hits += leaf.ship_placed as u8;
+
+            emit!(ProofVerified {
+                game: ctx.accounts.game.key(),
+                player,
+                attacked_field: leaf.index,
+                ship_placed: leaf.ship_placed
+            });
+
+            if let Some(length) = sunk_ship_length {
+                emit!(ShipSunk {
+                    game: ctx.accounts.game.key(),
+                    player,
+                    ship_id: leaf.ship_id,
+                    length
+                });
+            }
         }
 
-        if player == ctx.accounts.game.player1 {
-            ctx.accounts.game.player1_verified_proof_this_turn = true;
-        } else if player == ctx.accounts.game.player2 {
-            ctx.accounts.game.player2_verified_proof_this_turn = true;
+        charge_time_bank(&mut ctx.accounts.game, player, Clock::get()?.slot)?;
+
+        let game = &mut ctx.accounts.game;
+        let enemy_shots = if player == game.player1 {
+            game.player2_shots.len()
         } else {
-            return err!(CustomError::PlayerNotPartOfGame);
+            game.player1_shots.len()
+        };
+        if answers_this_turn(game, player).len() == enemy_shots {
+            if player == game.player1 {
+                game.player1_verified_proof_this_turn = true;
+            } else {
+                game.player2_verified_proof_this_turn = true;
+            }
         }
 
-        emit!(ProofVerified {
-            game: ctx.accounts.game.key(),
-            player: player,
-            attacked_field: leaf.index,
-            ship_placed: leaf.ship_placed
-        });
-
         msg!("{:?} is proving", player);
         msg!("Game: {:?}", ctx.accounts.game);
 
-        update_game_state(&mut ctx.accounts.game, leaf.ship_placed, player);
+        update_game_state(&mut ctx.accounts.game, hits, player);
 
         Ok(())
     }
 
-    pub fn claim_win(ctx: Context<ClaimWin>, table: [ProofField; 128]) -> Result<()> {
+    /// Ends the game in the caller's favour. The pot stays in the vault until the loser reveals
+    /// their board or misses the reveal deadline, see `reveal_board` and `forfeit_reveal`.
+    pub fn claim_win(ctx: Context<ClaimWin>, table: BoardTable) -> Result<()> {
         let player = *ctx.accounts.player.key;
         let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
 
-        let player = check_if_player_is_part_of_game(player, game)?;
+        let player = check_if_player_is_part_of_game(player, game, SESSION_CLAIM)?;
+        check_game_in_progress(game)?;
 
-        let current_slot = Clock::get()?.slot;
-        let turn_duration = TURN_DURATION as u64;
-
-        // Ignore turn expiration if the enemy has no remaining ship fields
         msg!("Player1 remaining ship fields: {}", game.player1_remaining_ship_fields);
         msg!("Player2 remaining ship fields: {}", game.player2_remaining_ship_fields);
         msg!("Player1: {:?}", game.player1);
         msg!("Player2: {:?}", game.player2);
         msg!("Player: {:?}", player);
-        if !((player == game.player1 && game.player2_remaining_ship_fields == 0)
-            || (player == game.player2 && game.player1_remaining_ship_fields == 0))
-        {
-            if current_slot < game.turn_start_slot + turn_duration {
-                return err!(CustomError::TurnNotExpired);
-            }
+        let reason = check_claim_win(game, player, Clock::get()?.slot)?;
+
+        verify_table(&table, player, game)?;
+        finish_game(game, player, reason)
+    }
+
+    /// Ends a game in which both fleets were sunk in the same turn. The caller proves their board
+    /// right away, both stakes are refunded once the opponent has revealed theirs.
+    pub fn claim_draw(ctx: Context<ClaimWin>, table: BoardTable) -> Result<()> {
+        let player = *ctx.accounts.player.key;
+        let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
+
+        let player = check_if_player_is_part_of_game(player, game, SESSION_CLAIM)?;
+        check_game_in_progress(game)?;
+
+        if !is_draw(game) {
+            return err!(CustomError::NotADraw);
         }
 
-        // if game.winner == Pubkey::default() {
-        //     return err!(CustomError::GameFinished);
-        // }
+        verify_table(&table, player, game)?;
+        finish_draw(game, player)
+    }
 
-        if player == game.player1
-            && ((!game.player2_attacked_this_turn || !game.player2_verified_proof_this_turn)
-                || game.player2_remaining_ship_fields == 0)
-            && game.player1_remaining_ship_fields > 0
-        {
-            verify_table(table, player, &game)?;
-            game.winner = game.player1;
-            emit!(GameFinished {
-                game: game.key(),
-                winner: game.player1
-            });
+    /// Concedes the game to the opponent. The resigning player still has to reveal their board.
+    pub fn resign(ctx: Context<ClaimWin>) -> Result<()> {
+        let player = *ctx.accounts.player.key;
+        let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
 
-            return Ok(());
-        } else if player == game.player2
-            && ((!game.player1_attacked_this_turn || !game.player1_verified_proof_this_turn)
-                || game.player1_remaining_ship_fields == 0)
-            && game.player2_remaining_ship_fields > 0
-        {
-            verify_table(table, player, &game)?;
-            game.winner = game.player2;
-            emit!(GameFinished {
-                game: game.key(),
-                winner: game.player2
-            });
+        let player = check_if_player_is_part_of_game(player, game, SESSION_RESIGN)?;
+        check_game_in_progress(game)?;
 
-            return Ok(());
+        let winner = if player == game.player1 {
+            game.player2
         } else {
-            return err!(CustomError::EnemyPlayedTurn);
+            game.player1
+        };
+
+        finish_game(game, winner, FinishReason::Resigned)
+    }
+
+    /// Cancels a game both players stopped playing and refunds both stakes: both still owe a move
+    /// and either the turn expired or both time banks ran out. Games one player can claim, because
+    /// a fleet sank or only the opponent ran out of time bank, can not be cancelled.
+    pub fn cancel_game(ctx: Context<ClaimWin>) -> Result<()> {
+        let player = *ctx.accounts.player.key;
+        let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
+
+        check_if_player_is_part_of_game(player, game, SESSION_CLAIM)?;
+        check_game_in_progress(game)?;
+
+        let reason = check_cancel(game, Clock::get()?.slot)?;
+
+        game.status = GameStatus::Cancelled;
+
+        emit!(GameCancelled {
+            game: game.key(),
+            reason
+        });
+
+        ctx.accounts.refund_stakes()
+    }
+
+    /// Called by the loser after `claim_win` or `resign` to prove that the board they played with was
+    /// legal and that every hit/miss answer they gave during the game was truthful. Settles the
+    /// game: the winner is paid the pot, after a draw both stakes are refunded.
+    pub fn reveal_board(ctx: Context<ClaimWin>, table: BoardTable) -> Result<()> {
+        let player = *ctx.accounts.player.key;
+        let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
+
+        let player = check_if_player_is_part_of_game(player, game, SESSION_PROVE)?;
+        let loser = check_reveal_pending(game)?;
+
+        if player != loser {
+            return err!(CustomError::NotTheLoser);
+        }
+
+        if Clock::get()?.slot > game.reveal_deadline_slot {
+            return err!(CustomError::RevealExpired);
+        }
+
+        verify_table(&table, player, game)?;
+        game.loser_revealed_board = true;
+        game.status = GameStatus::Finished;
+
+        emit!(BoardRevealed {
+            game: game.key(),
+            player
+        });
+
+        if game.winner == Pubkey::default() {
+            ctx.accounts.refund_stakes()
+        } else {
+            ctx.accounts.pay_out_pot()
+        }
+    }
+
+    /// Records that the loser did not reveal their board before the reveal deadline and pays the
+    /// pot to their opponent, which turns a draw into a win for the player who claimed it.
+    pub fn forfeit_reveal(ctx: Context<ClaimWin>) -> Result<()> {
+        let game: &mut Account<'_, Game> = &mut ctx.accounts.game;
+
+        let loser = check_reveal_pending(game)?;
+
+        if Clock::get()?.slot <= game.reveal_deadline_slot {
+            return err!(CustomError::RevealNotExpired);
+        }
+
+        game.status = GameStatus::Finished;
+        game.winner = if loser == game.player1 {
+            game.player2
+        } else {
+            game.player1
+        };
+
+        emit!(RevealForfeited {
+            game: game.key(),
+            player: loser
+        });
+
+        ctx.accounts.pay_out_pot()
+    }
+
+    /// Closes a finished or cancelled game and returns its rent to player1, who paid for it.
+    /// Tokens anyone sent to the game's token vault after it was settled go to player1 as well.
+    pub fn close_game(ctx: Context<CloseGame>) -> Result<()> {
+        let game = &ctx.accounts.game;
+
+        if game.status != GameStatus::Finished && game.status != GameStatus::Cancelled {
+            return err!(CustomError::GameNotClosable);
+        }
+
+        if let Some(game_token_vault) = &ctx.accounts.game_token_vault {
+            let token_program = required(&ctx.accounts.token_program)?;
+            let game_id = game.game_id.to_le_bytes();
+            let signer_seeds: &[&[&[u8]]] = &[&[b"game", &game_id, &[game.bump]]];
+
+            // A token account can only be closed once it is empty
+            if game_token_vault.amount > 0 {
+                let player1_token_account = required(&ctx.accounts.player1_token_account)?;
+                if player1_token_account.mint != game_token_vault.mint {
+                    return err!(CustomError::WagerMintMismatch);
+                }
+                transfer_tokens(
+                    token_program,
+                    game_token_vault,
+                    player1_token_account,
+                    &game.to_account_info(),
+                    signer_seeds,
+                    game_token_vault.amount,
+                )?;
+            }
+
+            token::close_account(CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                token::CloseAccount {
+                    account: game_token_vault.to_account_info(),
+                    destination: ctx.accounts.player1.to_account_info(),
+                    authority: game.to_account_info(),
+                },
+                signer_seeds,
+            ))?;
+        } else if game.wager_mint.is_some() {
+            return err!(CustomError::MissingTokenAccount);
+        }
+
+        emit!(GameClosed { game: game.key() });
+
+        Ok(())
+    }
+}
+
+fn is_queue_entry_expired(entry: &GamePlayer, max_entry_age_slots: u64, current_slot: u64) -> bool {
+    current_slot > entry.enqueued_slot.saturating_add(max_entry_age_slots)
+}
+
+fn finish_game(game: &mut Account<'_, Game>, winner: Pubkey, reason: FinishReason) -> Result<()> {
+    game.winner = winner;
+    game.revealer = if winner == game.player1 {
+        game.player2
+    } else {
+        game.player1
+    };
+    game.status = GameStatus::AwaitingReveal;
+    game.reveal_deadline_slot = Clock::get()?.slot + REVEAL_DURATION as u64;
+
+    emit!(GameFinished {
+        game: game.key(),
+        winner,
+        reason
+    });
+
+    Ok(())
+}
+
+/// Decides whether `player` can claim the game: either the enemy fleet is sunk and the player's own
+/// answers of this turn are in, or the enemy missed their move. The latter also ends games in
+/// which the player's own fleet is sunk but the enemy never answers the shots that could be a draw.
+fn check_claim_win(game: &Game, player: Pubkey, current_slot: u64) -> Result<FinishReason> {
+    if is_draw(game) {
+        return err!(CustomError::GameIsDraw);
+    }
+
+    let (enemy, own_ship_fields, enemy_ship_fields) = if player == game.player1 {
+        (
+            game.player2,
+            game.player1_remaining_ship_fields,
+            game.player2_remaining_ship_fields,
+        )
+    } else {
+        (
+            game.player1,
+            game.player2_remaining_ship_fields,
+            game.player1_remaining_ship_fields,
+        )
+    };
+
+    // The claimant's own answers this turn could still sink their fleet and make it a draw
+    if enemy_ship_fields == 0 && own_ship_fields > 0 {
+        if game.status == GameStatus::AwaitingProofs && owes_move(game, player) {
+            return err!(CustomError::AnswersPending);
         }
+        return Ok(FinishReason::FleetSunk);
+    }
+
+    // A timeout only wins against an idle enemy if the claimant did their part of the turn or
+    // still has time bank left while the enemy has none
+    let turn_expired = current_slot >= game.turn_start_slot + game.turn_duration_slots;
+    let enemy_exhausted = is_time_bank_exhausted(game, enemy, current_slot);
+    if !turn_expired && !enemy_exhausted {
+        return err!(CustomError::TurnNotExpired);
+    }
+    if !owes_move(game, enemy) {
+        return err!(CustomError::EnemyPlayedTurn);
+    }
+    let enemy_flagged = enemy_exhausted && !is_time_bank_exhausted(game, player, current_slot);
+    if owes_move(game, player) && !enemy_flagged {
+        return err!(CustomError::BothPlayersIdle);
     }
+
+    Ok(FinishReason::Timeout)
 }
 
-fn check_time_expired(game: &Game) -> Result<()> {
-    let current_slot = Clock::get()?.slot;
-    let turn_duration = TURN_DURATION as u64;
+/// Decides whether the game can be cancelled, see `cancel_game`.
+fn check_cancel(game: &Game, current_slot: u64) -> Result<CancelReason> {
+    if game.player1_remaining_ship_fields == 0 || game.player2_remaining_ship_fields == 0 {
+        return err!(CustomError::GameDecided);
+    }
 
-    if current_slot > game.turn_start_slot + turn_duration {
-        return err!(CustomError::TimeExpired);
+    let player1_flagged = is_time_bank_exhausted(game, game.player1, current_slot);
+    let player2_flagged = is_time_bank_exhausted(game, game.player2, current_slot);
+    if player1_flagged != player2_flagged {
+        return err!(CustomError::GameDecided);
     }
+
+    let turn_expired = current_slot >= game.turn_start_slot + game.turn_duration_slots;
+    let both_idle = owes_move(game, game.player1) && owes_move(game, game.player2);
+
+    if !both_idle {
+        return err!(CustomError::GameNotAbandoned);
+    }
+
+    if player1_flagged && player2_flagged {
+        Ok(CancelReason::BothOutOfTime)
+    } else if turn_expired {
+        Ok(CancelReason::BothTimedOut)
+    } else {
+        err!(CustomError::GameNotAbandoned)
+    }
+}
+
+/// Both fleets sank in the same turn, only possible in simultaneous mode. No one can attack once a
+/// fleet is sunk, so the counters can not both reach zero in different turns.
+fn is_draw(game: &Game) -> bool {
+    game.player1_remaining_ship_fields == 0 && game.player2_remaining_ship_fields == 0
+}
+
+/// Ends the game without a winner, the player who did not claim the draw has to reveal their board.
+fn finish_draw(game: &mut Account<'_, Game>, claimant: Pubkey) -> Result<()> {
+    game.winner = Pubkey::default();
+    game.revealer = if claimant == game.player1 {
+        game.player2
+    } else {
+        game.player1
+    };
+    game.status = GameStatus::AwaitingReveal;
+    game.reveal_deadline_slot = Clock::get()?.slot + REVEAL_DURATION as u64;
+
+    emit!(GameFinished {
+        game: game.key(),
+        winner: game.winner,
+        reason: FinishReason::Draw
+    });
+
     Ok(())
 }
 
-fn verify_table(table: [ProofField; 128], player: Pubkey, game: &Game) -> Result<()> {
-    let (root, _) = get_player_board_hash(player, game)?;
+/// Moves lamports out of an account owned by this program.
+fn move_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    let from_lamports = from
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(CustomError::InsufficientEscrow)?;
+    **from.try_borrow_mut_lamports()? = from_lamports;
+    **to.try_borrow_mut_lamports()? += amount;
+    Ok(())
+}
+
+fn check_fee(fee_bps: u16) -> Result<()> {
+    if fee_bps > MAX_FEE_BPS {
+        return err!(CustomError::FeeTooHigh);
+    }
+    Ok(())
+}
+
+fn check_turn_duration_bounds(min: u64, max: u64) -> Result<()> {
+    if min == 0 || min > max || max > MAX_TURN_DURATION_SLOTS {
+        return err!(CustomError::InvalidTurnDurationBounds);
+    }
+    Ok(())
+}
+
+fn check_queue_entry_age(max_entry_age_slots: u64) -> Result<()> {
+    if !(MIN_QUEUE_ENTRY_AGE_SLOTS..=MAX_QUEUE_ENTRY_AGE_SLOTS).contains(&max_entry_age_slots) {
+        return err!(CustomError::InvalidQueueEntryAge);
+    }
+    Ok(())
+}
+
+fn emit_config_updated(config: &Config) {
+    emit!(ConfigUpdated {
+        admin: config.admin,
+        fee_bps: config.fee_bps,
+        treasury: config.treasury,
+        min_turn_duration_slots: config.min_turn_duration_slots,
+        max_turn_duration_slots: config.max_turn_duration_slots,
+    });
+}
+
+fn transfer_tokens<'info>(
+    token_program: &Program<'info, Token>,
+    from: &Account<'info, TokenAccount>,
+    to: &Account<'info, TokenAccount>,
+    authority: &AccountInfo<'info>,
+    signer_seeds: &[&[&[u8]]],
+    amount: u64,
+) -> Result<()> {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    token::transfer(
+        CpiContext::new_with_signer(
+            token_program.to_account_info(),
+            token::Transfer {
+                from: from.to_account_info(),
+                to: to.to_account_info(),
+                authority: authority.clone(),
+            },
+            signer_seeds,
+        ),
+        amount,
+    )
+}
+
+/// Token accounts are optional because they are only needed for token wagers.
+fn required<T>(account: &Option<T>) -> Result<&T> {
+    account
+        .as_ref()
+        .ok_or_else(|| error!(CustomError::MissingTokenAccount))
+}
 
-    let mut ships_placed_counter = 0;
-    let mut ship_lengths = vec![0; 4]; // Counters for ships of length 2, 3, 4, 5
-    let mut visited = vec![false; 100]; // To track visited fields
-
-    // Identify ships and their lengths
-    for index in 0..100 {
-        if table[index].ship_placed && !visited[index] {
-            // Check all diagonally connected fields
-            let curr_row = index / 10;
-            let curr_col = index % 10;
-            let diagonal_directions = [(-1, -1), (-1, 1), (1, -1), (1, 1)];
-            for (dr, dc) in diagonal_directions.iter() {
-                let new_row = curr_row as isize + dr;
-                let new_col = curr_col as isize + dc;
-                if new_row >= 0 && new_row < 10 && new_col >= 0 && new_col < 10 {
-                    let new_index = (new_row * 10 + new_col) as usize;
-                    if table[new_index].ship_placed {
-                        return err!(CustomError::InvalidTable);
-                    }
+impl<'info> ClaimWin<'info> {
+    /// Pays both stakes to `game.winner`, minus the protocol fee the game was created with,
+    /// which goes to the game's treasury.
+    fn pay_out_pot(&self) -> Result<()> {
+        let game = &self.game;
+        let pot = game.wager_amount * 2;
+        let fee = (pot as u128 * game.fee_bps as u128 / BPS_DENOMINATOR as u128) as u64;
+
+        match game.wager_mint {
+            None => {
+                let vault = self.vault.to_account_info();
+                let winner = if game.winner == game.player1 {
+                    &self.player1
+                } else {
+                    &self.player2
+                };
+                move_lamports(&vault, &self.treasury, fee)?;
+                move_lamports(&vault, winner, pot - fee)
+            }
+            Some(wager_mint) => {
+                let game_token_vault = required(&self.game_token_vault)?;
+                let winner_token_account = if game.winner == game.player1 {
+                    required(&self.player1_token_account)?
+                } else {
+                    required(&self.player2_token_account)?
+                };
+                let treasury_token_account = required(&self.treasury_token_account)?;
+                if game_token_vault.mint != wager_mint
+                    || winner_token_account.mint != wager_mint
+                    || treasury_token_account.mint != wager_mint
+                {
+                    return err!(CustomError::WagerMintMismatch);
                 }
+                let token_program = required(&self.token_program)?;
+                let game_id = game.game_id.to_le_bytes();
+                let signer_seeds: &[&[&[u8]]] = &[&[b"game", &game_id, &[game.bump]]];
+                transfer_tokens(
+                    token_program,
+                    game_token_vault,
+                    treasury_token_account,
+                    &game.to_account_info(),
+                    signer_seeds,
+                    fee,
+                )?;
+                transfer_tokens(
+                    token_program,
+                    game_token_vault,
+                    winner_token_account,
+                    &game.to_account_info(),
+                    signer_seeds,
+                    pot - fee,
+                )
             }
+        }
+    }
 
-            let mut length = 0;
-            let mut stack = vec![index];
+    /// Returns each player's stake, games without a winner are not charged a fee.
+    fn refund_stakes(&self) -> Result<()> {
+        let game = &self.game;
 
-            while let Some(current) = stack.pop() {
-                if visited[current] {
-                    continue;
-                }
-                visited[current] = true;
-                length += 1;
-                let row = current / 10;
-                let col = current % 10;
-                let directions = [(-1, 0), (1, 0), (0, -1), (0, 1)];
-                for (dr, dc) in directions.iter() {
-                    let new_row = row as isize + dr;
-                    let new_col = col as isize + dc;
-                    if new_row >= 0 && new_row < 10 && new_col >= 0 && new_col < 10 {
-                        let new_index = (new_row * 10 + new_col) as usize;
-                        if table[new_index].ship_placed && !visited[new_index] {
-                            stack.push(new_index);
-                        }
-                    }
-                }
+        match game.wager_mint {
+            None => {
+                let vault = self.vault.to_account_info();
+                move_lamports(&vault, &self.player1, game.wager_amount)?;
+                move_lamports(&vault, &self.player2, game.wager_amount)
             }
-            if length > 5 || length < 2 {
-                return err!(CustomError::InvalidTable);
+            Some(wager_mint) => {
+                let game_token_vault = required(&self.game_token_vault)?;
+                let player1_token_account = required(&self.player1_token_account)?;
+                let player2_token_account = required(&self.player2_token_account)?;
+                if game_token_vault.mint != wager_mint
+                    || player1_token_account.mint != wager_mint
+                    || player2_token_account.mint != wager_mint
+                {
+                    return err!(CustomError::WagerMintMismatch);
+                }
+                let token_program = required(&self.token_program)?;
+                let game_id = game.game_id.to_le_bytes();
+                let signer_seeds: &[&[&[u8]]] = &[&[b"game", &game_id, &[game.bump]]];
+                transfer_tokens(
+                    token_program,
+                    game_token_vault,
+                    player1_token_account,
+                    &game.to_account_info(),
+                    signer_seeds,
+                    game.wager_amount,
+                )?;
+                transfer_tokens(
+                    token_program,
+                    game_token_vault,
+                    player2_token_account,
+                    &game.to_account_info(),
+                    signer_seeds,
+                    game.wager_amount,
+                )
             }
-            ship_lengths[length - 2] += 1;
-            ships_placed_counter += length;
         }
     }
+}
+
+fn check_status(game: &Game, expected: GameStatus) -> Result<()> {
+    check_game_in_progress(game)?;
+
+    if game.status != expected {
+        msg!("Game is {:?}, expected {:?}", game.status, expected);
+        return err!(CustomError::WrongGameStatus);
+    }
+    Ok(())
+}
+
+fn check_game_in_progress(game: &Game) -> Result<()> {
+    match game.status {
+        GameStatus::AwaitingAttacks | GameStatus::AwaitingProofs => Ok(()),
+        GameStatus::AwaitingReveal | GameStatus::Finished | GameStatus::Cancelled => {
+            err!(CustomError::GameFinished)
+        }
+    }
+}
 
-    // Validate ship counts
-    if ship_lengths != vec![1, 2, 1, 1] {
-        return err!(CustomError::InvalidTable);
+/// The game is decided once a fleet is sunk, further shots could only turn a win into a draw.
+fn check_fleets_afloat(game: &Game) -> Result<()> {
+    if game.player1_remaining_ship_fields == 0 || game.player2_remaining_ship_fields == 0 {
+        return err!(CustomError::FleetSunk);
     }
+    Ok(())
+}
 
-    let leaves: Result<Vec<Hash>> = table
+fn check_reveal_pending(game: &Game) -> Result<Pubkey> {
+    if game.status != GameStatus::AwaitingReveal {
+        return err!(CustomError::RevealNotPending);
+    }
+
+    Ok(game.revealer)
+}
+
+fn record_answer(game: &mut Game, player: Pubkey, leaf: &GameField) {
+    let answer = Answer {
+        turn: game.current_turn,
+        field: leaf.index,
+        ship_placed: leaf.ship_placed,
+        ship_id: leaf.ship_id,
+    };
+
+    if player == game.player1 {
+        game.player1_answers.push(answer);
+    } else {
+        game.player2_answers.push(answer);
+    }
+}
+
+/// Answers `player` gave about their own board in the current turn, they are always the
+/// last ones of the log.
+fn answers_this_turn(game: &Game, player: Pubkey) -> &[Answer] {
+    let answers = if player == game.player1 {
+        &game.player1_answers
+    } else {
+        &game.player2_answers
+    };
+
+    let first = answers
+        .iter()
+        .rposition(|answer| answer.turn != game.current_turn)
+        .map_or(0, |last_earlier| last_earlier + 1);
+    &answers[first..]
+}
+
+/// Counts a hit on ship `leaf.ship_id` of `player`, returns the ship's length if the hit sank it.
+fn record_hit(game: &mut Game, player: Pubkey, leaf: &GameField) -> Result<Option<u8>> {
+    if !leaf.ship_placed {
+        if leaf.ship_id != NO_SHIP {
+            return err!(CustomError::InvalidShipId);
+        }
+        return Ok(None);
+    }
+
+    let ship_id = leaf.ship_id as usize;
+    let Some(&length) = game.rule_set.fleet.get(ship_id) else {
+        return err!(CustomError::InvalidShipId);
+    };
+    let ship_hits = if player == game.player1 {
+        &mut game.player1_ship_hits
+    } else {
+        &mut game.player2_ship_hits
+    };
+
+    // A ship can not take more hits than it has fields
+    if ship_hits[ship_id] >= length {
+        return err!(CustomError::InvalidShipId);
+    }
+    ship_hits[ship_id] += 1;
+
+    Ok((ship_hits[ship_id] == length).then_some(length))
+}
+
+fn check_table_matches_answers(ship_ids: &[u8], player: Pubkey, game: &Game) -> Result<()> {
+    let answers = if player == game.player1 {
+        &game.player1_answers
+    } else {
+        &game.player2_answers
+    };
+
+    for answer in answers {
+        let ship_id = ship_ids[answer.field as usize];
+        if (ship_id != NO_SHIP) != answer.ship_placed || ship_id != answer.ship_id {
+            msg!(
+                "Field {} contradicts the answer given in turn {}",
+                answer.field,
+                answer.turn
+            );
+            return err!(CustomError::TableContradictsAnswers);
+        }
+    }
+
+    Ok(())
+}
+
+fn check_time_expired(game: &Game) -> Result<()> {
+    let current_slot = Clock::get()?.slot;
+    let turn_duration = game.turn_duration_slots;
+
+    if current_slot > game.turn_start_slot + turn_duration {
+        return err!(CustomError::TimeExpired);
+    }
+    Ok(())
+}
+
+/// Deducts the slots `player` spent on their current move from their time bank.
+fn charge_time_bank(game: &mut Game, player: Pubkey, current_slot: u64) -> Result<()> {
+    if game.time_bank_slots == 0 {
+        return Ok(());
+    }
+
+    let (time_bank_slots, clock_started_slot) = if player == game.player1 {
+        (
+            &mut game.player1_time_bank_slots,
+            &mut game.player1_clock_started_slot,
+        )
+    } else {
+        (
+            &mut game.player2_time_bank_slots,
+            &mut game.player2_clock_started_slot,
+        )
+    };
+
+    let spent_slots = current_slot.saturating_sub(*clock_started_slot);
+    if spent_slots > *time_bank_slots {
+        return err!(CustomError::TimeBankExhausted);
+    }
+    *time_bank_slots -= spent_slots;
+    *clock_started_slot = current_slot;
+    Ok(())
+}
+
+/// True if `player` still has to attack or answer in the current phase of the turn. Nobody attacks
+/// once a fleet is sunk, the game is left to be claimed.
+fn owes_move(game: &Game, player: Pubkey) -> bool {
+    let (attacked, verified) = if player == game.player1 {
+        (
+            game.player1_attacked_this_turn,
+            game.player1_verified_proof_this_turn,
+        )
+    } else {
+        (
+            game.player2_attacked_this_turn,
+            game.player2_verified_proof_this_turn,
+        )
+    };
+
+    match game.status {
+        GameStatus::AwaitingAttacks => !attacked && check_fleets_afloat(game).is_ok(),
+        GameStatus::AwaitingProofs => !verified,
+        _ => false,
+    }
+}
+
+/// True if `player` still owes a move this turn and has already spent more than their time bank on it.
+fn is_time_bank_exhausted(game: &Game, player: Pubkey, current_slot: u64) -> bool {
+    if game.time_bank_slots == 0 {
+        return false;
+    }
+
+    let (time_bank_slots, clock_started_slot) = if player == game.player1 {
+        (
+            game.player1_time_bank_slots,
+            game.player1_clock_started_slot,
+        )
+    } else {
+        (
+            game.player2_time_bank_slots,
+            game.player2_clock_started_slot,
+        )
+    };
+
+    owes_move(game, player) && current_slot.saturating_sub(clock_started_slot) > time_bank_slots
+}
+
+fn verify_table(table: &BoardTable, player: Pubkey, game: &Game) -> Result<()> {
+    let (root, _) = get_player_board_hash(player, game)?;
+    let rule_set = &game.rule_set;
+    let ship_ids = &table.ship_ids;
+
+    if ship_ids.len() != rule_set.board_fields() {
+        return err!(CustomError::TableSizeMismatch);
+    }
+
+    check_ships(ship_ids, rule_set)?;
+
+    // Padding leaves are never taken from the caller, so they always hash to the canonical value
+    let padding_leaves = (rule_set.board_fields()..rule_set.merkle_leaves())
+        .map(|index| Ok(hash_leaf(&GameField::padding(index as u8))));
+    let leaves: Result<Vec<Hash>> = ship_ids
         .iter()
         .enumerate()
-        .map(|(index, field)| {
+        .map(|(index, &ship_id)| {
             Ok(hash_leaf(&GameField {
                 index: index as u8,
-                ship_placed: field.ship_placed,
+                ship_placed: ship_id != NO_SHIP,
+                ship_id,
+                secret: field_secret(&table.seed, index as u8),
             }))
         })
+        .chain(padding_leaves)
         .collect();
 
-    if ships_placed_counter != 17 {
-        return err!(CustomError::InvalidTable);
-    }
+    check_table_matches_answers(ship_ids, player, game)?;
 
     let mut leaves = leaves?;
 
     msg!("Leaves[0]: {:?}", to_hex_string(&leaves[0].to_bytes()));
-    msg!("Leaves[127]: {:?}", to_hex_string(&leaves[127].to_bytes()));
+    msg!(
+        "Leaves[{}]: {:?}",
+        leaves.len() - 1,
+        to_hex_string(&leaves[leaves.len() - 1].to_bytes())
+    );
 
     while leaves.len() > 1 {
         let mut next_level = Vec::new();
@@ -326,7 +1349,7 @@ fn verify_table(table: [ProofField; 128], player: Pubkey, game: &Game) -> Result
                     &[leaves[i].to_bytes(), leaves[i + 1].to_bytes()].concat(),
                 ));
             } else {
-                next_level.push(leaves[i].clone());
+                next_level.push(leaves[i]);
             }
         }
         leaves = next_level;
@@ -345,66 +1368,145 @@ fn verify_table(table: [ProofField; 128], player: Pubkey, game: &Game) -> Result
     }
 }
 
+/// Checks that the fields of every ship id form one straight ship of the length the fleet gives
+/// that id, and that ships are only as close to each other as the adjacency policy allows.
+fn check_ships(ship_ids: &[u8], rule_set: &RuleSet) -> Result<()> {
+    let width = rule_set.width as isize;
+    let height = rule_set.height as isize;
+    let mut ship_fields = vec![Vec::new(); rule_set.fleet.len()];
+
+    for (index, &ship_id) in ship_ids.iter().enumerate() {
+        match ship_id {
+            NO_SHIP => {}
+            ship_id if (ship_id as usize) < rule_set.fleet.len() => {
+                ship_fields[ship_id as usize].push(index)
+            }
+            _ => {
+                msg!("Field {} has ship id {}", index, ship_id);
+                return err!(CustomError::InvalidShipId);
+            }
+        }
+    }
+
+    for (ship_id, fields) in ship_fields.iter().enumerate() {
+        if fields.len() != rule_set.fleet[ship_id] as usize {
+            return err!(CustomError::InvalidTable);
+        }
+
+        // Fields are in board order, so a straight ship steps by 1 within a row or by `width`
+        let first = fields[0];
+        let last = fields[fields.len() - 1];
+        let row_length = rule_set.width as usize;
+        let horizontal =
+            last - first == fields.len() - 1 && first / row_length == last / row_length;
+        let vertical = fields
+            .windows(2)
+            .all(|pair| pair[1] - pair[0] == row_length);
+        if !horizontal && !vertical {
+            msg!("Ship {} is not straight", ship_id);
+            return err!(CustomError::InvalidShipShape);
+        }
+    }
+
+    for (index, &ship_id) in ship_ids
+        .iter()
+        .enumerate()
+        .filter(|(_, &ship_id)| ship_id != NO_SHIP)
+    {
+        let (row, col) = (index as isize / width, index as isize % width);
+        for (dr, dc) in rule_set.adjacency.forbidden_neighbours() {
+            let (r, c) = (row + dr, col + dc);
+            if !(0..height).contains(&r) || !(0..width).contains(&c) {
+                continue;
+            }
+            let neighbour = ship_ids[(r * width + c) as usize];
+            if neighbour != NO_SHIP && neighbour != ship_id {
+                msg!("Ship {} touches ship {}", ship_id, neighbour);
+                return err!(CustomError::ShipsTooClose);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 #[inline(never)]
 fn hash_leaf(leaf: &GameField) -> Hash {
     hash(&leaf.serialize())
 }
 
+/// Secret of the field at `index`: the first 8 bytes of `blake3(seed || index)`, little endian.
+/// Revealing the secrets of answered fields does not give away the seed or any other secret.
+fn field_secret(seed: &[u8; 32], index: u8) -> u64 {
+    let digest = hash(&[seed.as_ref(), &[index]].concat()).to_bytes();
+    u64::from_le_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Expects the player's own address, session keys are resolved by `check_if_player_is_part_of_game`.
 #[inline(never)]
 fn get_player_board_hash(player: Pubkey, game: &Game) -> Result<(BoardHash, Pubkey)> {
-    if player == game.player1 || player == game.player1_session_key {
+    if player == game.player1 {
         return Ok((game.player1_board_hash, game.player1));
     }
 
-    if player == game.player2 || player == game.player2_session_key {
+    if player == game.player2 {
         return Ok((game.player2_board_hash, game.player2));
     }
 
-    return err!(CustomError::PlayerNotPartOfGame);
+    err!(CustomError::PlayerNotPartOfGame)
 }
 
-fn check_tried_verifying(tried_verifying: &mut bool) -> Result<()> {
-    if *tried_verifying {
+fn check_tried_verifying(verified_proof: bool) -> Result<()> {
+    if verified_proof {
         return err!(CustomError::AlreadyTriedVerifing);
     }
-    *tried_verifying = true;
     Ok(())
 }
 
-fn check_field_index(field_index: u8, expected_field_index: u8) -> Result<()> {
+fn check_field_index(field_index: u8, enemy_shots: &[u8], answers: &[Answer]) -> Result<()> {
     msg!(
-        "Field index: {}, Expected field index {}",
+        "Field index: {}, Expected field indices {:?}",
         field_index,
-        expected_field_index
+        enemy_shots
     );
-    if field_index != expected_field_index {
+    if !enemy_shots.contains(&field_index) {
         return Err(CustomError::WrongProvingFieldIndex.into());
     }
+    if answers.iter().any(|answer| answer.field == field_index) {
+        return err!(CustomError::FieldAlreadyProven);
+    }
     Ok(())
 }
 
 fn verify_merkle_proof(
     hashed_leaf: Hash,
-    proof: [BoardHash; 7],
+    proof: Vec<BoardHash>,
     root: BoardHash,
     proving_field_index: u8,
-    game: &mut Account<'_, Game>,
+    game: &Game,
     player: Pubkey,
 ) -> Result<bool> {
-    // let field_player1_attacked_this_turn = game.field_player1_attacked_this_turn;
-    // let field_player2_attacked_this_turn = game.field_player2_attacked_this_turn;
-    // let mut player1_tried_verifing_this_turn = game.player1_tried_verifing_this_turn;
-    // let mut player2_tried_verifing_this_turn = game.player2_tried_verifing_this_turn;
-
     // msg!("Game: {:?}", game);
 
     if player == game.player1 {
-        check_tried_verifying(&mut game.player1_tried_verifing_this_turn)?;
-        check_field_index(proving_field_index, game.field_player2_attacked_this_turn)?;
+        check_tried_verifying(game.player1_verified_proof_this_turn)?;
+        check_field_index(
+            proving_field_index,
+            &game.player2_shots,
+            answers_this_turn(game, player),
+        )?;
     }
     if player == game.player2 {
-        check_tried_verifying(&mut game.player2_tried_verifing_this_turn)?;
-        check_field_index(proving_field_index, game.field_player1_attacked_this_turn)?;
+        check_tried_verifying(game.player2_verified_proof_this_turn)?;
+        check_field_index(
+            proving_field_index,
+            &game.player1_shots,
+            answers_this_turn(game, player),
+        )?;
+    }
+
+    if proof.len() != game.rule_set.proof_depth() {
+        return err!(CustomError::InvalidProof);
     }
 
     let mut last_hash = hashed_leaf;
@@ -428,11 +1530,11 @@ fn verify_merkle_proof(
     // }
 
     let mut dir_array: Vec<u8> = Vec::new();
-    let mut field_index = proving_field_index + 1; // Replace with your initial value
+    let mut field_index = proving_field_index as u16 + 1; // Replace with your initial value
 
-    while dir_array.len() < 7 {
-        dir_array.push(field_index % 2);
-        field_index = (field_index + 1) / 2; // Equivalent to `Math.ceil(fieldIndex / 2)`
+    while dir_array.len() < proof.len() {
+        dir_array.push((field_index % 2) as u8);
+        field_index = field_index.div_ceil(2); // Equivalent to `Math.ceil(fieldIndex / 2)`
     }
 
     for (i, dir) in dir_array.iter().enumerate() {
@@ -467,134 +1569,267 @@ fn verify_merkle_proof(
     Ok(last_hash == Hash::new_from_array(root))
 }
 
-fn check_if_player_is_part_of_game(player: Pubkey, game: &Game) -> Result<Pubkey> {
-    if player == game.player1 || player == game.player1_session_key {
+/// Resolves the signer to the player they act for. A session key is only accepted until it
+/// expires and for the actions in `permission`, the player's own wallet may do anything.
+fn check_if_player_is_part_of_game(player: Pubkey, game: &Game, permission: u8) -> Result<Pubkey> {
+    if player == game.player1 || player == game.player2 {
+        return Ok(player);
+    }
+
+    if player == game.player1_session_key {
+        check_session_scope(&game.player1_session_scope, permission, Clock::get()?.slot)?;
         return Ok(game.player1);
     }
 
-    if player == game.player2 || player == game.player2_session_key {
+    if player == game.player2_session_key {
+        check_session_scope(&game.player2_session_scope, permission, Clock::get()?.slot)?;
         return Ok(game.player2);
     }
 
     msg!("Player: {:?}", player);
     msg!("Game: {:?}", game);
 
-    return err!(CustomError::PlayerNotPartOfGame);
+    err!(CustomError::PlayerNotPartOfGame)
+}
+
+fn check_session_scope(scope: &SessionScope, permission: u8, current_slot: u64) -> Result<()> {
+    if current_slot > scope.expires_slot {
+        return err!(CustomError::SessionKeyExpired);
+    }
+
+    if scope.permissions & permission != permission {
+        return err!(CustomError::SessionKeyNotPermitted);
+    }
+
+    Ok(())
+}
+
+/// In alternating mode only `side_to_move` attacks and only the other player answers, so the
+/// parts of the turn the players sit out are marked as done when the turn starts.
+fn skip_idle_moves(game: &mut Game) {
+    if game.rule_set.turn_mode != TurnMode::Alternating {
+        return;
+    }
+
+    if game.side_to_move == game.player1 {
+        game.player1_verified_proof_this_turn = true;
+        game.player2_attacked_this_turn = true;
+    } else {
+        game.player2_verified_proof_this_turn = true;
+        game.player1_attacked_this_turn = true;
+    }
+}
+
+/// Resets the per-turn state once both players answered, in alternating mode the attacker changes.
+fn start_next_turn(game: &mut Game, current_slot: u64) {
+    game.current_turn += 1;
+    game.status = GameStatus::AwaitingAttacks;
+    game.player1_attacked_this_turn = false;
+    game.player2_attacked_this_turn = false;
+    game.player1_shots.clear();
+    game.player2_shots.clear();
+    game.player1_verified_proof_this_turn = false;
+    game.player2_verified_proof_this_turn = false;
+    if game.rule_set.turn_mode == TurnMode::Alternating {
+        game.side_to_move = if game.side_to_move == game.player1 {
+            game.player2
+        } else {
+            game.player1
+        };
+    }
+    skip_idle_moves(game);
+    game.turn_start_slot = current_slot;
+    game.player1_clock_started_slot = current_slot;
+    game.player2_clock_started_slot = current_slot;
 }
 
-fn update_game_state(game: &mut Account<'_, Game>, ship_hit: bool, player: Pubkey) {
+fn update_game_state(game: &mut Account<'_, Game>, hits: u8, player: Pubkey) {
     msg!("Player: {}", player);
     msg!("Player {:?}", player);
     msg!("Player1: {:?}", game.player1);
-    // msg!("game.field_player2_attacked_this_turn: {}", proving_field_index);
     // msg!("Proving field: {}", proving_field_index);
 
     msg!("Player1 {}", game.player1);
-    msg!("game.field_player1_attacked_this_turn: {}", game.field_player1_attacked_this_turn);
+    msg!("game.player1_shots: {:?}", game.player1_shots);
 
-    if player == game.player1 && ship_hit {
-        game.player1_remaining_ship_fields -= 1;
-    } else if player == game.player2 && ship_hit
-    {
-        game.player2_remaining_ship_fields -= 1;
+    if player == game.player1 {
+        game.player1_remaining_ship_fields -= hits;
+    } else if player == game.player2 {
+        game.player2_remaining_ship_fields -= hits;
     }
 
-    if game.player1_attacked_this_turn
-        && game.player2_attacked_this_turn
-        && game.player1_tried_verifing_this_turn
-        && game.player2_tried_verifing_this_turn
-    {
-        game.current_turn += 1;
-        game.player1_attacked_this_turn = false;
-        game.player2_attacked_this_turn = false;
-        game.player1_tried_verifing_this_turn = false;
-        game.player2_tried_verifing_this_turn = false;
-        game.field_player1_attacked_this_turn = 255;
-        game.field_player2_attacked_this_turn = 255;
-        game.player1_verified_proof_this_turn = false;
-        game.player2_verified_proof_this_turn = false;
-        game.turn_start_slot = Clock::get().unwrap().slot;
+    if game.player1_verified_proof_this_turn && game.player2_verified_proof_this_turn {
+        start_next_turn(game, Clock::get().unwrap().slot);
         emit!(TurnFinished {
             game: game.key(),
             turn: game.current_turn - 1
         });
-
-        // if game.player1_remaining_ship_fields == 0 && game.player2_remaining_ship_fields == 0 {
-        //     game.winner = Pubkey::default();
-        //     emit!(GameFinished {
-        //         game: game.key(),
-        //         winner: Pubkey::default()
-        //     });
-        // } else if game.player1_remaining_ship_fields == 0 {
-        //     game.winner = game.player2;
-        //     emit!(GameFinished {
-        //         game: game.key(),
-        //         winner: game.player2
-        //     });
-        // } else if game.player2_remaining_ship_fields == 0 {
-        //     game.winner = game.player1;
-        //     emit!(GameFinished {
-        //         game: game.key(),
-        //         winner: game.player1
-        //     });
-        // }
     }
 }
 
+/// Merkle proof of one answered shot, `proof` holds `RuleSet::proof_depth` hashes.
 #[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
-pub struct ProofField {
-    ship_placed: bool,
-    // secret: u64,
-    // salt: u64,
+pub struct FieldProof {
+    proof: Vec<BoardHash>,
+    leaf: GameField,
+}
+
+/// A player's whole board, revealed at the end of the game. The secrets of all fields are derived
+/// from `seed`, so the table costs one byte per field and fits in a transaction on any board size.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+pub struct BoardTable {
+    seed: [u8; 32],
+    /// Ship id of every field in board order, `NO_SHIP` for water
+    ship_ids: Vec<u8>,
 }
 
+/// A single board leaf. `secret` is a per-cell salt derived from the board owner's seed, see
+/// `field_secret`, so the opponent cannot recover `ship_placed` from the leaf hash by trying both values.
+/// `ship_id` tells which ship the field belongs to, so sinking it can be proven.
 #[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
 pub struct GameField {
     index: u8,
     ship_placed: bool,
-    // secret: u64,
+    ship_id: u8,
+    secret: u64,
 }
 
 impl GameField {
+    /// Leaves past the last board field only pad the tree, they never hold a ship or a secret.
+    fn padding(index: u8) -> Self {
+        GameField {
+            index,
+            ship_placed: false,
+            ship_id: NO_SHIP,
+            secret: 0,
+        }
+    }
+
+    // Leaf preimage layout: index (1 byte) | ship_placed (1 byte) | ship_id (1 byte) | secret (8 bytes, LE)
     fn serialize(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+        let mut buf = Vec::with_capacity(11);
         buf.push(self.index);
         buf.push(self.ship_placed as u8);
-        // buf.extend_from_slice(&self.secret.to_le_bytes());
+        buf.push(self.ship_id);
+        buf.extend_from_slice(&self.secret.to_le_bytes());
         buf
     }
 }
 
 #[derive(Accounts)]
+#[instruction(page: u16)]
 pub struct InitializeQueue<'info> {
-    #[account(init, seeds = [b"queue"], bump,  payer = user, space = 8 + 32 * 100)]
-    // Adjust space as needed
+    #[account(init, seeds = [b"queue", page.to_le_bytes().as_ref()], bump, payer = user, space = 8 + Queue::INIT_SPACE)]
+    pub queue: Account<'info, Queue>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(page: u16)]
+pub struct LeaveQueue<'info> {
+    #[account(mut, seeds = [b"queue", page.to_le_bytes().as_ref()], bump)]
+    pub queue: Account<'info, Queue>,
+    #[account(mut, seeds = [b"queue_ticket", player.key().as_ref()], bump, constraint = queue_ticket.page == page @ CustomError::WrongQueuePage)]
+    pub queue_ticket: Account<'info, QueueTicket>,
+    #[account(mut)]
+    pub player: Signer<'info>,
+    // Only for token wagers
+    #[account(mut, token::authority = player)]
+    pub player_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, token::authority = queue_ticket)]
+    pub ticket_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+#[instruction(page: u16)]
+pub struct PruneQueue<'info> {
+    #[account(mut, seeds = [b"queue", page.to_le_bytes().as_ref()], bump)]
     pub queue: Account<'info, Queue>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeGameCounter<'info> {
+    #[account(init, seeds = [b"game_counter"], bump, payer = user, space = 8 + GameCounter::INIT_SPACE)]
+    pub game_counter: Account<'info, GameCounter>,
     #[account(mut)]
     pub user: Signer<'info>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(init, seeds = [b"config"], bump, payer = admin, space = 8 + Config::INIT_SPACE)]
+    pub config: Account<'info, Config>,
+    #[account(mut)]
+    pub admin: Signer<'info>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()) @ CustomError::NotUpgradeAuthority)]
+    pub program: Program<'info, program::Solship>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(admin.key()) @ CustomError::NotUpgradeAuthority)]
+    pub program_data: Account<'info, ProgramData>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    #[account(mut, seeds = [b"config"], bump, has_one = admin)]
+    pub config: Account<'info, Config>,
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+#[instruction(page: u16)]
 pub struct JoinQueue<'info> {
-    #[account(mut, seeds = [b"queue"], bump)]
+    #[account(mut, seeds = [b"queue", page.to_le_bytes().as_ref()], bump)]
     pub queue: Account<'info, Queue>,
+    #[account(seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
+    #[account(init_if_needed, seeds = [b"queue_ticket", player.key().as_ref()], bump, payer = player, space = 8 + QueueTicket::INIT_SPACE)]
+    pub queue_ticket: Account<'info, QueueTicket>,
     #[account(mut)]
     pub player: Signer<'info>,
     pub session_key: Signer<'info>,
     pub system_program: Program<'info, System>,
+    // Only for token wagers
+    pub wager_mint: Option<Account<'info, Mint>>,
+    #[account(mut, token::mint = wager_mint, token::authority = player)]
+    pub player_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(init_if_needed, payer = player, associated_token::mint = wager_mint, associated_token::authority = queue_ticket)]
+    pub ticket_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
 }
 
 #[derive(Accounts)]
-#[instruction(enemy: Pubkey)]
+#[instruction(queue_page: u16, enemy: Pubkey)]
 pub struct CreateGame<'info> {
-    #[account(init, seeds = [b"game", player.key().as_ref() , enemy.as_ref()], bump, payer = player, space = 8 + 2 * 32 + 2 * 32 + 2 * 32 + 1  + 2 + 2 + 2 + 2 + 2 + 8 + 32)]
-    pub game: Account<'info, Game>,
+    #[account(mut, seeds = [b"game_counter"], bump)]
+    pub game_counter: Account<'info, GameCounter>,
+    #[account(init, seeds = [b"game", game_counter.next_game_id.to_le_bytes().as_ref()], bump, payer = player, space = 8 + Game::INIT_SPACE)]
+    pub game: Box<Account<'info, Game>>,
+    #[account(init, seeds = [b"vault", game.key().as_ref()], bump, payer = player, space = 8 + Vault::INIT_SPACE)]
+    pub vault: Account<'info, Vault>,
+    // Only for token wagers
+    pub wager_mint: Option<Account<'info, Mint>>,
+    #[account(mut, token::mint = wager_mint, token::authority = player)]
+    pub player_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, associated_token::mint = wager_mint, associated_token::authority = enemy_queue_ticket)]
+    pub enemy_ticket_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(init, payer = player, associated_token::mint = wager_mint, associated_token::authority = game)]
+    pub game_token_vault: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+    pub associated_token_program: Option<Program<'info, AssociatedToken>>,
     #[account(mut)]
     pub player: Signer<'info>,
     pub session_key: Signer<'info>,
-    #[account(mut, seeds = [b"queue"], bump)]
+    #[account(mut, seeds = [b"queue", queue_page.to_le_bytes().as_ref()], bump)]
     pub queue: Account<'info, Queue>,
+    #[account(mut, seeds = [b"queue_ticket", enemy.as_ref()], bump)]
+    pub enemy_queue_ticket: Account<'info, QueueTicket>,
+    #[account(seeds = [b"config"], bump)]
+    pub config: Account<'info, Config>,
     pub system_program: Program<'info, System>,
 }
 
@@ -610,50 +1845,407 @@ pub struct ClaimWin<'info> {
     pub player: Signer<'info>,
     #[account(mut)]
     pub game: Box<Account<'info, Game>>,
+    #[account(mut, seeds = [b"vault", game.key().as_ref()], bump)]
+    pub vault: Account<'info, Vault>,
+    /// CHECK: Receives the protocol fee, checked against `game.treasury`
+    #[account(mut, address = game.treasury)]
+    pub treasury: UncheckedAccount<'info>,
+    /// CHECK: Receives the pot if player1 wins, checked against `game.player1`
+    #[account(mut, address = game.player1)]
+    pub player1: UncheckedAccount<'info>,
+    /// CHECK: Receives the pot if player2 wins, checked against `game.player2`
+    #[account(mut, address = game.player2)]
+    pub player2: UncheckedAccount<'info>,
+    // Only for token wagers
+    #[account(mut, token::authority = game)]
+    pub game_token_vault: Option<Account<'info, TokenAccount>>,
+    #[account(mut, token::authority = player1)]
+    pub player1_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, token::authority = player2)]
+    pub player2_token_account: Option<Account<'info, TokenAccount>>,
+    #[account(mut, token::authority = treasury)]
+    pub treasury_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
+}
+
+#[derive(Accounts)]
+pub struct CloseGame<'info> {
+    #[account(mut, has_one = player1, close = player1)]
+    pub game: Box<Account<'info, Game>>,
+    #[account(mut, seeds = [b"vault", game.key().as_ref()], bump, close = player1)]
+    pub vault: Account<'info, Vault>,
+    /// CHECK: Only receives the rent, checked against `game.player1`
+    #[account(mut)]
+    pub player1: UncheckedAccount<'info>,
+    // Only for token wagers
+    #[account(mut, token::authority = game)]
+    pub game_token_vault: Option<Account<'info, TokenAccount>>,
+    /// Receives tokens left in the vault, only needed if it is not empty
+    #[account(mut, token::authority = player1)]
+    pub player1_token_account: Option<Account<'info, TokenAccount>>,
+    pub token_program: Option<Program<'info, Token>>,
 }
 
 // #[account(zero_copy)]
 #[account]
-#[derive(Debug)]
+#[derive(Debug, InitSpace)]
 pub struct Game {
+    pub bump: u8,
+    pub game_id: u64,
+    pub rule_set: RuleSet,
     pub player1: Pubkey,
     pub player2: Pubkey,
     pub player1_session_key: Pubkey,
     pub player2_session_key: Pubkey,
-    pub player1_board_hash: BoardHash,
-    pub player2_board_hash: BoardHash,
-    pub current_turn: u8,
-    // pub player1_attacked_fields: [bool; 100],
-    // pub player2_attacked_fields: [bool; 100],
+    pub player1_session_scope: SessionScope,
+    pub player2_session_scope: SessionScope,
+    pub player1_board_hash: [u8; 32],
+    pub player2_board_hash: [u8; 32],
+    pub current_turn: u16,
+    pub status: GameStatus,
+    pub player1_attacked_fields: FieldBitboard,
+    pub player2_attacked_fields: FieldBitboard,
+    // Every answer the player gave about their own board, checked against the revealed table
+    #[max_len(MAX_BOARD_FIELDS)]
+    pub player1_answers: Vec<Answer>,
+    #[max_len(MAX_BOARD_FIELDS)]
+    pub player2_answers: Vec<Answer>,
     pub player1_attacked_this_turn: bool,
     pub player2_attacked_this_turn: bool,
-    pub player1_tried_verifing_this_turn: bool,
-    pub player2_tried_verifing_this_turn: bool,
     pub player1_verified_proof_this_turn: bool,
     pub player2_verified_proof_this_turn: bool,
-    pub field_player1_attacked_this_turn: u8,
-    pub field_player2_attacked_this_turn: u8,
+    /// Fields each player attacked this turn, to be answered by the other player
+    #[max_len(MAX_SHOTS_PER_TURN)]
+    pub player1_shots: Vec<u8>,
+    #[max_len(MAX_SHOTS_PER_TURN)]
+    pub player2_shots: Vec<u8>,
+    /// Only player allowed to attack this turn in alternating mode, unused in simultaneous mode
+    pub side_to_move: Pubkey,
     pub player1_remaining_ship_fields: u8,
     pub player2_remaining_ship_fields: u8,
+    /// Hits taken by every ship of each player's fleet, indexed by ship id
+    #[max_len(MAX_FLEET_SIZE)]
+    pub player1_ship_hits: Vec<u8>,
+    #[max_len(MAX_FLEET_SIZE)]
+    pub player2_ship_hits: Vec<u8>,
+    /// Slots each player has to finish their part of a turn
+    pub turn_duration_slots: u64,
     pub turn_start_slot: u64,
+    /// Total slots each player may spend on their moves over the whole game, 0 if disabled
+    pub time_bank_slots: u64,
+    pub player1_time_bank_slots: u64,
+    pub player2_time_bank_slots: u64,
+    /// Slot from which the current move of each player is charged to their time bank
+    pub player1_clock_started_slot: u64,
+    pub player2_clock_started_slot: u64,
+    /// `Pubkey::default()` while the game runs and after a draw
     pub winner: Pubkey,
+    /// Player who has to reveal their board once the game is over, the loser or, after a draw,
+    /// the player who did not claim it
+    pub revealer: Pubkey,
+    pub reveal_deadline_slot: u64,
+    pub loser_revealed_board: bool,
+    /// Stake of each player, the vault holds twice this amount until the game is settled
+    pub wager_amount: u64,
+    /// `None` for lamport stakes held by `Vault`, otherwise the mint of the stakes held
+    /// by the game's associated token account
+    pub wager_mint: Option<Pubkey>,
+    /// Protocol fee and treasury at the time the game was created, later config updates do not
+    /// apply to running games
+    pub fee_bps: u16,
+    pub treasury: Pubkey,
 }
 
-#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone)]
+impl Game {
+    /// Size of the salvo `player` has to fire this turn, smaller than the rule set's once
+    /// fewer fields are left to attack.
+    fn shots_this_turn(&self, player: Pubkey) -> usize {
+        let attacked_fields = if player == self.player1 {
+            &self.player1_attacked_fields
+        } else {
+            &self.player2_attacked_fields
+        };
+        let unattacked_fields = self.rule_set.board_fields() - attacked_fields.count();
+
+        match self.rule_set.shots_per_turn {
+            ShotsPerTurn::Fixed { shots } => unattacked_fields.min(shots as usize),
+            ShotsPerTurn::UnsunkShips => {
+                let ship_hits = if player == self.player1 {
+                    &self.player1_ship_hits
+                } else {
+                    &self.player2_ship_hits
+                };
+                let unsunk_ships = ship_hits
+                    .iter()
+                    .zip(&self.rule_set.fleet)
+                    .filter(|(hits, length)| hits < length)
+                    .count();
+                unattacked_fields.min(unsunk_ships)
+            }
+        }
+    }
+}
+
+/// Holds the lamport stakes of both players of `game`, stored at `[b"vault", game]`.
+#[account]
+#[derive(InitSpace)]
+pub struct Vault {
+    pub game: Pubkey,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum GameStatus {
+    /// Both players still have to attack this turn
+    AwaitingAttacks,
+    /// Both players attacked and have to prove the field they were attacked on
+    AwaitingProofs,
+    /// A winner or a draw is known and the loser, or the player who did not claim the draw,
+    /// has to reveal their board before the pot is settled
+    AwaitingReveal,
+    Finished,
+    Cancelled,
+}
+
+/// Board dimensions and fleet both players agreed on in matchmaking.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, PartialEq, Eq, InitSpace)]
+pub struct RuleSet {
+    pub width: u8,
+    pub height: u8,
+    /// Length of every ship, e.g. `[5, 4, 3, 3, 2]` for the classic fleet
+    #[max_len(MAX_FLEET_SIZE)]
+    pub fleet: Vec<u8>,
+    pub adjacency: AdjacencyPolicy,
+    pub shots_per_turn: ShotsPerTurn,
+    pub turn_mode: TurnMode,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum TurnMode {
+    /// Both players attack every turn and then both answer
+    Simultaneous,
+    /// Players take turns attacking, the defender answers before the other side moves
+    Alternating,
+}
+
+/// How many fields each player attacks per turn.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum ShotsPerTurn {
+    /// The same salvo size every turn, 1 for the classic game
+    Fixed { shots: u8 },
+    /// One shot for every ship of the attacker's fleet that is still afloat
+    UnsunkShips,
+}
+
+/// How close two ships may be placed to each other. Ships are always straight lines.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq, InitSpace)]
+pub enum AdjacencyPolicy {
+    /// Ships may touch on any side or corner
+    MayTouch,
+    /// Ships may touch end to end or side to side, but not corner to corner
+    NoDiagonalTouch,
+    /// Ships may not touch at all
+    Classic,
+}
+
+impl AdjacencyPolicy {
+    /// Offsets of the neighbouring fields that must not belong to another ship.
+    fn forbidden_neighbours(&self) -> &'static [(isize, isize)] {
+        match self {
+            AdjacencyPolicy::MayTouch => &[],
+            AdjacencyPolicy::NoDiagonalTouch => &[(-1, -1), (-1, 1), (1, -1), (1, 1)],
+            AdjacencyPolicy::Classic => &[
+                (-1, -1),
+                (-1, 0),
+                (-1, 1),
+                (0, -1),
+                (0, 1),
+                (1, -1),
+                (1, 0),
+                (1, 1),
+            ],
+        }
+    }
+}
+
+impl RuleSet {
+    fn validate(&self) -> Result<()> {
+        let longest_ship = self.width.max(self.height);
+        let ship_fields: usize = self.fleet.iter().map(|&length| length as usize).sum();
+
+        if !(2..=MAX_BOARD_SIDE).contains(&self.width)
+            || !(2..=MAX_BOARD_SIDE).contains(&self.height)
+            || self.fleet.is_empty()
+            || self.fleet.len() > MAX_FLEET_SIZE
+            || self
+                .fleet
+                .iter()
+                .any(|&length| !(1..=longest_ship).contains(&length))
+            || ship_fields >= self.board_fields()
+            || !self.shots_per_turn.is_valid()
+        {
+            return err!(CustomError::InvalidRuleSet);
+        }
+        Ok(())
+    }
+
+    fn board_fields(&self) -> usize {
+        self.width as usize * self.height as usize
+    }
+
+    // The board is padded to the next power of two so the Merkle tree is complete
+    fn merkle_leaves(&self) -> usize {
+        self.board_fields().next_power_of_two()
+    }
+
+    fn proof_depth(&self) -> usize {
+        self.merkle_leaves().trailing_zeros() as usize
+    }
+
+    /// Fits in a `u8` because `validate` keeps it below the number of board fields.
+    fn ship_fields(&self) -> u8 {
+        self.fleet.iter().sum()
+    }
+}
+
+impl ShotsPerTurn {
+    fn is_valid(&self) -> bool {
+        match *self {
+            ShotsPerTurn::Fixed { shots } => (1..=MAX_SHOTS_PER_TURN).contains(&shots),
+            // The fleet has at most `MAX_FLEET_SIZE` ships
+            ShotsPerTurn::UnsunkShips => true,
+        }
+    }
+}
+
+/// One bit per board field, bit `i` is set once field `i` has been attacked.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, Default, InitSpace)]
+pub struct FieldBitboard {
+    bits: [u128; 2],
+}
+
+impl FieldBitboard {
+    fn contains(&self, field: u8) -> bool {
+        self.bits[field as usize / 128] & (1 << (field % 128)) != 0
+    }
+
+    fn count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|bits| bits.count_ones() as usize)
+            .sum()
+    }
+
+    fn mark(&mut self, field: u8) -> Result<()> {
+        if self.contains(field) {
+            return err!(CustomError::FieldAlreadyAttacked);
+        }
+        self.bits[field as usize / 128] |= 1 << (field % 128);
+        Ok(())
+    }
+}
+
+/// A hit/miss answer proven in `verify_proof`. Every field is attacked at most once,
+/// so the log never grows beyond `MAX_BOARD_FIELDS` entries.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, InitSpace)]
+pub struct Answer {
+    turn: u16,
+    field: u8,
+    ship_placed: bool,
+    ship_id: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, InitSpace)]
 pub struct GamePlayer {
     address: Pubkey,
     session_key: Pubkey,
-    board_root: BoardHash,
+    session_scope: SessionScope,
+    board_root: [u8; 32],
+    enqueued_slot: u64,
+    wager_mint: Option<Pubkey>,
+    settings: GameSettings,
+}
+
+/// Everything two players have to agree on to be matched, besides the stake's mint.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, InitSpace)]
+pub struct GameSettings {
+    /// Stake of each player, in lamports or in tokens of the wager mint
+    pub wager_amount: u64,
+    /// Slots each player has to finish their part of a turn
+    pub turn_duration_slots: u64,
+    /// Total slots each player may spend on their moves over the whole game, 0 disables it
+    pub time_bank_slots: u64,
+    pub rule_set: RuleSet,
+}
+
+/// What a player's session key may do in a game. The player's own wallet is never restricted.
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, InitSpace)]
+pub struct SessionScope {
+    /// Last slot in which the session key is accepted
+    pub expires_slot: u64,
+    /// `SESSION_*` bits of the actions the session key may take
+    pub permissions: u8,
+}
+
+impl SessionScope {
+    fn validate(&self) -> Result<()> {
+        if self.permissions & !SESSION_ALL != 0 {
+            return err!(CustomError::InvalidSessionPermissions);
+        }
+
+        Ok(())
+    }
 }
 
+/// Program-wide settings, stored at `[b"config"]`.
 #[account]
+#[derive(InitSpace)]
+pub struct Config {
+    pub admin: Pubkey,
+    /// Cut of every settled pot, in basis points
+    pub fee_bps: u16,
+    pub treasury: Pubkey,
+    /// Bounds of the turn duration players can choose when joining the queue
+    pub min_turn_duration_slots: u64,
+    pub max_turn_duration_slots: u64,
+}
+
+/// Hands out the ids that games are derived from, so the same players can have any number
+/// of games with each other, now or later.
+#[account]
+#[derive(InitSpace)]
+pub struct GameCounter {
+    pub next_game_id: u64,
+}
+
+/// One page of the matchmaking queue, stored at `[b"queue", page]`.
+#[account]
+#[derive(InitSpace)]
 pub struct Queue {
+    pub page: u16,
+    #[max_len(QUEUE_PAGE_CAPACITY)]
     pub players: Vec<GamePlayer>,
+    /// Entries older than this can be pruned by anyone and can no longer be matched
+    pub max_entry_age_slots: u64,
+}
+
+/// Tracks whether a player is waiting in any queue page and escrows their stake meanwhile.
+#[account]
+#[derive(InitSpace)]
+pub struct QueueTicket {
+    pub bump: u8,
+    pub page: u16,
+    pub in_queue: bool,
+    pub expires_slot: u64,
+    pub wager_amount: u64,
+    pub wager_mint: Option<Pubkey>,
 }
 
 #[event]
 pub struct GameStarted {
     game: Pubkey,
+    pub game_id: u64,
+    pub wager_amount: u64,
+    pub wager_mint: Option<Pubkey>,
     pub player1: Pubkey,
     pub player2: Pubkey,
 }
@@ -661,7 +2253,7 @@ pub struct GameStarted {
 #[event]
 pub struct TurnFinished {
     pub game: Pubkey,
-    pub turn: u8,
+    pub turn: u16,
 }
 
 #[event]
@@ -679,10 +2271,70 @@ pub struct ProofVerified {
     ship_placed: bool,
 }
 
+/// `player` proved that the last field of their ship `ship_id` was hit.
+#[event]
+pub struct ShipSunk {
+    game: Pubkey,
+    player: Pubkey,
+    ship_id: u8,
+    length: u8,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    FleetSunk,
+    Timeout,
+    Resigned,
+    /// Both fleets sank in the same turn, `GameFinished::winner` is `Pubkey::default()`
+    Draw,
+}
+
 #[event]
 pub struct GameFinished {
     game: Pubkey,
     winner: Pubkey,
+    reason: FinishReason,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelReason {
+    /// The turn expired while both players still owed a move
+    BothTimedOut,
+    /// Both players ran out of time bank while they still owed a move
+    BothOutOfTime,
+}
+
+/// The game ended without a winner and both stakes were refunded.
+#[event]
+pub struct GameCancelled {
+    game: Pubkey,
+    reason: CancelReason,
+}
+
+#[event]
+pub struct BoardRevealed {
+    game: Pubkey,
+    player: Pubkey,
+}
+
+#[event]
+pub struct RevealForfeited {
+    game: Pubkey,
+    player: Pubkey,
+}
+
+#[event]
+pub struct GameClosed {
+    game: Pubkey,
+}
+
+#[event]
+pub struct ConfigUpdated {
+    admin: Pubkey,
+    fee_bps: u16,
+    treasury: Pubkey,
+    min_turn_duration_slots: u64,
+    max_turn_duration_slots: u64,
 }
 
 #[error_code]
@@ -707,6 +2359,98 @@ pub enum CustomError {
     EnemyPlayedTurn,
     #[msg("Player already attacked this turn")]
     AlreadyAttackedThisTurn,
+    #[msg("Field is outside of the board")]
+    FieldOutOfRange,
+    #[msg("Field was already attacked")]
+    FieldAlreadyAttacked,
+    #[msg("Instruction is not allowed in the current game status")]
+    WrongGameStatus,
+    #[msg("Board reveal is not pending")]
+    RevealNotPending,
+    #[msg("Only the loser, or the player who did not claim a draw, reveals their board")]
+    NotTheLoser,
+    #[msg("Reveal deadline expired")]
+    RevealExpired,
+    #[msg("Reveal deadline has not expired")]
+    RevealNotExpired,
+    #[msg("Table contradicts answers given during the game")]
+    TableContradictsAnswers,
+    #[msg("Only finished or cancelled games can be closed")]
+    GameNotClosable,
+    #[msg("Player is already in the queue")]
+    AlreadyInQueue,
+    #[msg("Player is not in the queue")]
+    PlayerNotInQueue,
+    #[msg("Queue entry expired")]
+    QueueEntryExpired,
+    #[msg("Queue page is full")]
+    QueueFull,
+    #[msg("Players must wager the same amount")]
+    WagerMismatch,
+    #[msg("Players must wager the same token")]
+    WagerMintMismatch,
+    #[msg("Token account required for token wagers is missing")]
+    MissingTokenAccount,
+    #[msg("Stake of an expired queue entry has to be withdrawn with leave_queue first")]
+    StakeNotWithdrawn,
+    #[msg("Escrow account holds less than the expected stake")]
+    InsufficientEscrow,
+    #[msg("Fee can not exceed 1,000 basis points")]
+    FeeTooHigh,
+    #[msg("Turn duration bounds must be positive, ordered and at most about a week")]
+    InvalidTurnDurationBounds,
+    #[msg("Turn duration is outside of the configured bounds")]
+    TurnDurationOutOfBounds,
+    #[msg("Players must choose the same turn duration")]
+    TurnDurationMismatch,
+    #[msg("Players must choose the same time bank")]
+    TimeBankMismatch,
+    #[msg("Time bank exhausted")]
+    TimeBankExhausted,
+    #[msg("Board size or fleet is not playable")]
+    InvalidRuleSet,
+    #[msg("Players must choose the same rule set")]
+    RuleSetMismatch,
+    #[msg("Table does not cover every board field")]
+    TableSizeMismatch,
+    #[msg("Ships must be straight horizontal or vertical lines")]
+    InvalidShipShape,
+    #[msg("Salvo does not have the number of shots allowed this turn")]
+    WrongShotCount,
+    #[msg("Field was already proven this turn")]
+    FieldAlreadyProven,
+    #[msg("Ship id does not match the fleet")]
+    InvalidShipId,
+    #[msg("Ships are placed closer than the adjacency policy allows")]
+    ShipsTooClose,
+    #[msg("It is not this player's move")]
+    NotYourTurn,
+    #[msg("Both fleets were sunk, the game is a draw")]
+    GameIsDraw,
+    #[msg("Game is not a draw")]
+    NotADraw,
+    #[msg("Player has to answer this turn's shots first")]
+    AnswersPending,
+    #[msg("Both players let the turn expire, the game can only be cancelled")]
+    BothPlayersIdle,
+    #[msg("Game is still being played")]
+    GameNotAbandoned,
+    #[msg("Session permissions contain unknown bits")]
+    InvalidSessionPermissions,
+    #[msg("Session key has expired")]
+    SessionKeyExpired,
+    #[msg("Session key is not permitted to take this action")]
+    SessionKeyNotPermitted,
+    #[msg("Player is queued on another page")]
+    WrongQueuePage,
+    #[msg("Only the program's upgrade authority can initialize the config")]
+    NotUpgradeAuthority,
+    #[msg("One of the players can claim the game, it can not be cancelled")]
+    GameDecided,
+    #[msg("A fleet was sunk, the game can only be claimed")]
+    FleetSunk,
+    #[msg("Queue entries must live between about a minute and a day")]
+    InvalidQueueEntryAge,
 }
 
 #[inline(never)]
@@ -717,3 +2461,670 @@ fn to_hex_string(bytes: &[u8; 32]) -> String {
         .collect::<String>())
     .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_set(width: u8, height: u8, fleet: &[u8], adjacency: AdjacencyPolicy) -> RuleSet {
+        RuleSet {
+            width,
+            height,
+            fleet: fleet.to_vec(),
+            adjacency,
+            shots_per_turn: ShotsPerTurn::Fixed { shots: 1 },
+            turn_mode: TurnMode::Simultaneous,
+        }
+    }
+
+    /// Places ship `i` of the fleet at `ships[i]`: its first field as (row, col) and whether it
+    /// runs to the right or downwards.
+    fn board(rule_set: &RuleSet, ships: &[(usize, usize, bool)]) -> Vec<u8> {
+        let width = rule_set.width as usize;
+        let mut ship_ids = vec![NO_SHIP; rule_set.board_fields()];
+        for (ship_id, &(row, col, horizontal)) in ships.iter().enumerate() {
+            for i in 0..rule_set.fleet[ship_id] as usize {
+                let (r, c) = if horizontal {
+                    (row, col + i)
+                } else {
+                    (row + i, col)
+                };
+                ship_ids[r * width + c] = ship_id as u8;
+            }
+        }
+        ship_ids
+    }
+
+    fn classic() -> RuleSet {
+        rule_set(10, 10, &[5, 4, 3, 3, 2], AdjacencyPolicy::Classic)
+    }
+
+    #[test]
+    fn check_ships_accepts_straight_ships_apart() {
+        // `getFixedBoard1` of the TS tests
+        let rule_set = classic();
+        let ship_ids = board(
+            &rule_set,
+            &[
+                (0, 0, true),
+                (2, 2, false),
+                (7, 5, true),
+                (3, 8, false),
+                (9, 5, true),
+            ],
+        );
+        assert!(check_ships(&ship_ids, &rule_set).is_ok());
+    }
+
+    #[test]
+    fn check_ships_rejects_bent_ship() {
+        let rule_set = rule_set(10, 10, &[3], AdjacencyPolicy::Classic);
+        let mut ship_ids = vec![NO_SHIP; 100];
+        // L shape: (0,0), (0,1), (1,1)
+        for index in [0, 1, 11] {
+            ship_ids[index] = 0;
+        }
+        assert_eq!(
+            check_ships(&ship_ids, &rule_set).unwrap_err(),
+            error!(CustomError::InvalidShipShape)
+        );
+    }
+
+    #[test]
+    fn check_ships_rejects_ship_wrapping_around_row_end() {
+        let rule_set = rule_set(10, 10, &[3], AdjacencyPolicy::Classic);
+        let mut ship_ids = vec![NO_SHIP; 100];
+        // Consecutive indices, but (0,8), (0,9), (1,0)
+        for index in [8, 9, 10] {
+            ship_ids[index] = 0;
+        }
+        assert_eq!(
+            check_ships(&ship_ids, &rule_set).unwrap_err(),
+            error!(CustomError::InvalidShipShape)
+        );
+    }
+
+    #[test]
+    fn check_ships_rejects_wrong_ship_length() {
+        let rule_set = rule_set(10, 10, &[3], AdjacencyPolicy::Classic);
+        let mut ship_ids = vec![NO_SHIP; 100];
+        ship_ids[0] = 0;
+        ship_ids[1] = 0;
+        assert_eq!(
+            check_ships(&ship_ids, &rule_set).unwrap_err(),
+            error!(CustomError::InvalidTable)
+        );
+    }
+
+    #[test]
+    fn check_ships_rejects_unknown_ship_id() {
+        let rule_set = rule_set(10, 10, &[2], AdjacencyPolicy::MayTouch);
+        let mut ship_ids = board(&rule_set, &[(0, 0, true)]);
+        ship_ids[99] = 1;
+        assert_eq!(
+            check_ships(&ship_ids, &rule_set).unwrap_err(),
+            error!(CustomError::InvalidShipId)
+        );
+    }
+
+    #[test]
+    fn check_ships_applies_adjacency_policy() {
+        // The second ship touches the first one at a corner, or continues right after its end
+        let diagonal = [(0, 0, true), (1, 2, true)];
+        let end_to_end = [(0, 0, true), (0, 2, true)];
+
+        for (adjacency, diagonal_ok, end_to_end_ok) in [
+            (AdjacencyPolicy::MayTouch, true, true),
+            (AdjacencyPolicy::NoDiagonalTouch, false, true),
+            (AdjacencyPolicy::Classic, false, false),
+        ] {
+            let rule_set = rule_set(10, 10, &[2, 2], adjacency);
+            for (ships, ok) in [(&diagonal, diagonal_ok), (&end_to_end, end_to_end_ok)] {
+                let result = check_ships(&board(&rule_set, ships), &rule_set);
+                match ok {
+                    true => assert!(result.is_ok(), "{:?} {:?}", adjacency, ships),
+                    false => assert_eq!(result.unwrap_err(), error!(CustomError::ShipsTooClose)),
+                }
+            }
+        }
+    }
+
+    fn game(rule_set: RuleSet) -> Game {
+        let player1 = Pubkey::new_unique();
+        let player2 = Pubkey::new_unique();
+        let session_scope = SessionScope {
+            expires_slot: u64::MAX,
+            permissions: SESSION_ALL,
+        };
+        Game {
+            bump: 0,
+            game_id: 0,
+            player1_ship_hits: vec![0; rule_set.fleet.len()],
+            player2_ship_hits: vec![0; rule_set.fleet.len()],
+            player1_remaining_ship_fields: rule_set.ship_fields(),
+            player2_remaining_ship_fields: rule_set.ship_fields(),
+            rule_set,
+            player1,
+            player2,
+            player1_session_key: player1,
+            player2_session_key: player2,
+            player1_session_scope: session_scope,
+            player2_session_scope: session_scope,
+            player1_board_hash: [0; 32],
+            player2_board_hash: [0; 32],
+            current_turn: 1,
+            status: GameStatus::AwaitingAttacks,
+            player1_attacked_fields: FieldBitboard::default(),
+            player2_attacked_fields: FieldBitboard::default(),
+            player1_answers: Vec::new(),
+            player2_answers: Vec::new(),
+            player1_attacked_this_turn: false,
+            player2_attacked_this_turn: false,
+            player1_verified_proof_this_turn: false,
+            player2_verified_proof_this_turn: false,
+            player1_shots: Vec::new(),
+            player2_shots: Vec::new(),
+            side_to_move: player1,
+            turn_duration_slots: 75,
+            turn_start_slot: 0,
+            time_bank_slots: 0,
+            player1_time_bank_slots: 0,
+            player2_time_bank_slots: 0,
+            player1_clock_started_slot: 0,
+            player2_clock_started_slot: 0,
+            winner: Pubkey::default(),
+            revealer: Pubkey::default(),
+            reveal_deadline_slot: 0,
+            loser_revealed_board: false,
+            wager_amount: 0,
+            wager_mint: None,
+            fee_bps: 0,
+            treasury: Pubkey::default(),
+        }
+    }
+
+    fn answer(turn: u16, field: u8) -> Answer {
+        Answer {
+            turn,
+            field,
+            ship_placed: false,
+            ship_id: NO_SHIP,
+        }
+    }
+
+    #[test]
+    fn rule_set_validate_accepts_supported_boards() {
+        assert!(classic().validate().is_ok());
+        assert!(rule_set(8, 8, &[4, 3, 2], AdjacencyPolicy::MayTouch)
+            .validate()
+            .is_ok());
+        assert!(
+            rule_set(16, 16, &[16; MAX_FLEET_SIZE], AdjacencyPolicy::MayTouch)
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn rule_set_validate_rejects_unsupported_boards() {
+        let invalid = [
+            rule_set(1, 10, &[1], AdjacencyPolicy::Classic),
+            rule_set(10, MAX_BOARD_SIDE + 1, &[2], AdjacencyPolicy::Classic),
+            rule_set(10, 10, &[], AdjacencyPolicy::Classic),
+            rule_set(10, 10, &[2; MAX_FLEET_SIZE + 1], AdjacencyPolicy::MayTouch),
+            rule_set(10, 10, &[0], AdjacencyPolicy::Classic),
+            rule_set(10, 10, &[11], AdjacencyPolicy::Classic),
+            // No water left
+            rule_set(2, 2, &[2, 2], AdjacencyPolicy::MayTouch),
+        ];
+        for rule_set in invalid {
+            assert_eq!(
+                rule_set.validate().unwrap_err(),
+                error!(CustomError::InvalidRuleSet),
+                "{:?}",
+                rule_set
+            );
+        }
+
+        for shots in [0, MAX_SHOTS_PER_TURN + 1] {
+            let mut rule_set = classic();
+            rule_set.shots_per_turn = ShotsPerTurn::Fixed { shots };
+            assert_eq!(
+                rule_set.validate().unwrap_err(),
+                error!(CustomError::InvalidRuleSet)
+            );
+        }
+    }
+
+    #[test]
+    fn rule_set_derives_tree_size() {
+        let classic = classic();
+        assert_eq!(classic.board_fields(), 100);
+        assert_eq!(classic.merkle_leaves(), 128);
+        assert_eq!(classic.proof_depth(), 7);
+        assert_eq!(classic.ship_fields(), 17);
+
+        let largest = rule_set(16, 16, &[2], AdjacencyPolicy::Classic);
+        assert_eq!(largest.merkle_leaves(), MAX_BOARD_FIELDS);
+        assert_eq!(largest.proof_depth(), 8);
+    }
+
+    #[test]
+    fn field_bitboard_marks_every_field_once() {
+        let mut bitboard = FieldBitboard::default();
+        for field in [0, 127, 128, 255] {
+            assert!(!bitboard.contains(field));
+            bitboard.mark(field).unwrap();
+            assert!(bitboard.contains(field));
+        }
+        assert_eq!(bitboard.count(), 4);
+        assert!(!bitboard.contains(1));
+        assert_eq!(
+            bitboard.mark(128).unwrap_err(),
+            error!(CustomError::FieldAlreadyAttacked)
+        );
+        assert_eq!(bitboard.count(), 4);
+    }
+
+    #[test]
+    fn answers_this_turn_are_the_tail_of_the_log() {
+        let mut game = game(classic());
+        let player = game.player2;
+        game.player2_answers = vec![answer(1, 10), answer(2, 11), answer(3, 12), answer(3, 13)];
+
+        game.current_turn = 3;
+        let fields: Vec<u8> = answers_this_turn(&game, player)
+            .iter()
+            .map(|answer| answer.field)
+            .collect();
+        assert_eq!(fields, [12, 13]);
+
+        game.current_turn = 4;
+        assert!(answers_this_turn(&game, player).is_empty());
+        assert!(answers_this_turn(&game, game.player1).is_empty());
+    }
+
+    #[test]
+    fn shots_this_turn_are_capped_by_unattacked_fields() {
+        let mut rule_set = rule_set(2, 2, &[1], AdjacencyPolicy::MayTouch);
+        rule_set.shots_per_turn = ShotsPerTurn::Fixed { shots: 3 };
+        let mut game = game(rule_set);
+        let player = game.player1;
+        assert_eq!(game.shots_this_turn(player), 3);
+
+        game.player1_attacked_fields.mark(0).unwrap();
+        game.player1_attacked_fields.mark(1).unwrap();
+        assert_eq!(game.shots_this_turn(player), 2);
+        assert_eq!(game.shots_this_turn(game.player2), 3);
+    }
+
+    #[test]
+    fn shots_this_turn_count_unsunk_ships_in_salvo_mode() {
+        let mut rule_set = classic();
+        rule_set.shots_per_turn = ShotsPerTurn::UnsunkShips;
+        let mut game = game(rule_set);
+        let player = game.player1;
+        assert_eq!(game.shots_this_turn(player), 5);
+
+        // The carrier is sunk, the battleship only hit
+        game.player1_ship_hits = vec![5, 1, 0, 0, 0];
+        assert_eq!(game.shots_this_turn(player), 4);
+        assert_eq!(game.shots_this_turn(game.player2), 5);
+    }
+
+    #[test]
+    fn attacks_stop_once_a_fleet_is_sunk() {
+        let mut game = game(classic());
+        assert!(check_fleets_afloat(&game).is_ok());
+        assert!(!is_draw(&game));
+
+        game.player2_remaining_ship_fields = 0;
+        assert_eq!(
+            check_fleets_afloat(&game).unwrap_err(),
+            error!(CustomError::FleetSunk)
+        );
+        assert!(!is_draw(&game));
+
+        game.player1_remaining_ship_fields = 0;
+        assert!(is_draw(&game));
+    }
+
+    #[test]
+    fn owes_move_follows_the_phase_of_the_turn() {
+        let mut game = game(classic());
+        let (player1, player2) = (game.player1, game.player2);
+        assert!(owes_move(&game, player1) && owes_move(&game, player2));
+
+        game.player1_attacked_this_turn = true;
+        assert!(!owes_move(&game, player1) && owes_move(&game, player2));
+
+        game.player2_attacked_this_turn = true;
+        game.status = GameStatus::AwaitingProofs;
+        assert!(owes_move(&game, player1) && owes_move(&game, player2));
+
+        game.player2_verified_proof_this_turn = true;
+        assert!(owes_move(&game, player1) && !owes_move(&game, player2));
+
+        // Nobody attacks once a fleet is sunk
+        game.status = GameStatus::AwaitingAttacks;
+        game.player1_attacked_this_turn = false;
+        game.player2_attacked_this_turn = false;
+        game.player2_remaining_ship_fields = 0;
+        assert!(!owes_move(&game, player1) && !owes_move(&game, player2));
+
+        game.status = GameStatus::AwaitingReveal;
+        game.player2_remaining_ship_fields = 1;
+        assert!(!owes_move(&game, player1) && !owes_move(&game, player2));
+    }
+
+    #[test]
+    fn claim_win_needs_an_expired_turn_and_an_idle_enemy() {
+        let mut game = game(classic());
+        let (player1, player2) = (game.player1, game.player2);
+        assert_eq!(
+            check_claim_win(&game, player1, 74).unwrap_err(),
+            error!(CustomError::TurnNotExpired)
+        );
+        assert_eq!(
+            check_claim_win(&game, player1, 75).unwrap_err(),
+            error!(CustomError::BothPlayersIdle)
+        );
+
+        game.player1_attacked_this_turn = true;
+        assert_eq!(
+            check_claim_win(&game, player1, 75).unwrap(),
+            FinishReason::Timeout
+        );
+        assert_eq!(
+            check_claim_win(&game, player2, 75).unwrap_err(),
+            error!(CustomError::EnemyPlayedTurn)
+        );
+    }
+
+    #[test]
+    fn claim_win_on_a_sunk_fleet_waits_for_own_answers() {
+        let mut game = game(classic());
+        let (player1, player2) = (game.player1, game.player2);
+        game.status = GameStatus::AwaitingProofs;
+        game.player1_attacked_this_turn = true;
+        game.player2_attacked_this_turn = true;
+        game.player2_remaining_ship_fields = 0;
+        assert_eq!(
+            check_claim_win(&game, player1, 0).unwrap_err(),
+            error!(CustomError::AnswersPending)
+        );
+
+        game.player1_verified_proof_this_turn = true;
+        assert_eq!(
+            check_claim_win(&game, player1, 0).unwrap(),
+            FinishReason::FleetSunk
+        );
+        assert_eq!(
+            check_claim_win(&game, player2, 75).unwrap_err(),
+            error!(CustomError::EnemyPlayedTurn)
+        );
+
+        game.player1_remaining_ship_fields = 0;
+        assert_eq!(
+            check_claim_win(&game, player1, 0).unwrap_err(),
+            error!(CustomError::GameIsDraw)
+        );
+    }
+
+    #[test]
+    fn claim_win_lets_a_sunk_player_claim_unanswered_shots() {
+        let mut game = game(classic());
+        let (player1, player2) = (game.player1, game.player2);
+        game.status = GameStatus::AwaitingProofs;
+        game.player1_attacked_this_turn = true;
+        game.player2_attacked_this_turn = true;
+        game.player1_verified_proof_this_turn = true;
+        game.player1_remaining_ship_fields = 0;
+
+        // Player2's answers could still sink their own fleet, so they can not claim the win
+        assert_eq!(
+            check_claim_win(&game, player2, 75).unwrap_err(),
+            error!(CustomError::AnswersPending)
+        );
+        assert_eq!(
+            check_cancel(&game, 75).unwrap_err(),
+            error!(CustomError::GameDecided)
+        );
+        assert_eq!(
+            check_claim_win(&game, player1, 74).unwrap_err(),
+            error!(CustomError::TurnNotExpired)
+        );
+        assert_eq!(
+            check_claim_win(&game, player1, 75).unwrap(),
+            FinishReason::Timeout
+        );
+    }
+
+    #[test]
+    fn cancel_needs_both_players_idle() {
+        let mut game = game(classic());
+        assert_eq!(
+            check_cancel(&game, 74).unwrap_err(),
+            error!(CustomError::GameNotAbandoned)
+        );
+        assert_eq!(check_cancel(&game, 75).unwrap(), CancelReason::BothTimedOut);
+
+        game.time_bank_slots = 60;
+        game.player1_time_bank_slots = 20;
+        game.player2_time_bank_slots = 30;
+        assert_eq!(
+            check_cancel(&game, 31).unwrap(),
+            CancelReason::BothOutOfTime
+        );
+
+        // Only player1 ran out of time bank, player2 can claim the win instead
+        assert_eq!(
+            check_cancel(&game, 25).unwrap_err(),
+            error!(CustomError::GameDecided)
+        );
+        assert_eq!(
+            check_claim_win(&game, game.player2, 25).unwrap(),
+            FinishReason::Timeout
+        );
+
+        game.time_bank_slots = 0;
+        game.player1_attacked_this_turn = true;
+        assert_eq!(
+            check_cancel(&game, 75).unwrap_err(),
+            error!(CustomError::GameNotAbandoned)
+        );
+    }
+
+    #[test]
+    fn charge_time_bank_deducts_the_slots_of_the_move() {
+        let mut game = game(classic());
+        let player = game.player1;
+        charge_time_bank(&mut game, player, 500).unwrap();
+        assert_eq!(game.player1_clock_started_slot, 0);
+
+        game.time_bank_slots = 100;
+        game.player1_time_bank_slots = 100;
+        game.player1_clock_started_slot = 10;
+        charge_time_bank(&mut game, player, 40).unwrap();
+        assert_eq!(game.player1_time_bank_slots, 70);
+        assert_eq!(game.player1_clock_started_slot, 40);
+        assert_eq!(game.player2_time_bank_slots, 0);
+
+        // Spending the whole bank is fine, going past it is not
+        charge_time_bank(&mut game, player, 110).unwrap();
+        assert_eq!(game.player1_time_bank_slots, 0);
+        assert_eq!(
+            charge_time_bank(&mut game, player, 111).unwrap_err(),
+            error!(CustomError::TimeBankExhausted)
+        );
+    }
+
+    #[test]
+    fn time_bank_is_exhausted_only_while_owing_a_move() {
+        let mut game = game(classic());
+        let player = game.player2;
+        assert!(!is_time_bank_exhausted(&game, player, u64::MAX));
+
+        game.time_bank_slots = 100;
+        game.player1_time_bank_slots = 100;
+        game.player2_time_bank_slots = 30;
+        game.player2_clock_started_slot = 10;
+        assert!(!is_time_bank_exhausted(&game, player, 40));
+        assert!(is_time_bank_exhausted(&game, player, 41));
+        assert!(!is_time_bank_exhausted(&game, game.player1, 41));
+
+        game.player2_attacked_this_turn = true;
+        assert!(!is_time_bank_exhausted(&game, player, 41));
+    }
+
+    #[test]
+    fn session_scope_limits_expiry_and_actions() {
+        let scope = SessionScope {
+            expires_slot: 100,
+            permissions: SESSION_ATTACK | SESSION_PROVE,
+        };
+        assert!(check_session_scope(&scope, SESSION_ATTACK, 100).is_ok());
+        assert!(check_session_scope(&scope, SESSION_PROVE, 0).is_ok());
+        assert_eq!(
+            check_session_scope(&scope, SESSION_ATTACK, 101).unwrap_err(),
+            error!(CustomError::SessionKeyExpired)
+        );
+        for permission in [SESSION_CLAIM, SESSION_RESIGN, SESSION_ALL] {
+            assert_eq!(
+                check_session_scope(&scope, permission, 0).unwrap_err(),
+                error!(CustomError::SessionKeyNotPermitted)
+            );
+        }
+
+        assert!(scope.validate().is_ok());
+        let scope = SessionScope {
+            expires_slot: 100,
+            permissions: SESSION_ALL + 1,
+        };
+        assert_eq!(
+            scope.validate().unwrap_err(),
+            error!(CustomError::InvalidSessionPermissions)
+        );
+    }
+
+    #[test]
+    fn player_wallets_are_not_limited_by_the_session_scope() {
+        let mut game = game(classic());
+        game.player1_session_key = Pubkey::new_unique();
+        game.player1_session_scope = SessionScope {
+            expires_slot: 0,
+            permissions: 0,
+        };
+        let player1 = game.player1;
+        assert_eq!(
+            check_if_player_is_part_of_game(player1, &game, SESSION_RESIGN).unwrap(),
+            player1
+        );
+        assert_eq!(
+            check_if_player_is_part_of_game(Pubkey::new_unique(), &game, SESSION_ATTACK)
+                .unwrap_err(),
+            error!(CustomError::PlayerNotPartOfGame)
+        );
+    }
+
+    #[test]
+    fn turn_duration_bounds_are_ordered_and_capped() {
+        assert!(check_turn_duration_bounds(1, 1).is_ok());
+        assert!(check_turn_duration_bounds(15, MAX_TURN_DURATION_SLOTS).is_ok());
+        for (min, max) in [(0, 75), (76, 75), (15, MAX_TURN_DURATION_SLOTS + 1)] {
+            assert_eq!(
+                check_turn_duration_bounds(min, max).unwrap_err(),
+                error!(CustomError::InvalidTurnDurationBounds)
+            );
+        }
+    }
+
+    #[test]
+    fn queue_entry_age_is_bounded() {
+        assert!(check_queue_entry_age(MIN_QUEUE_ENTRY_AGE_SLOTS).is_ok());
+        assert!(check_queue_entry_age(MAX_QUEUE_ENTRY_AGE_SLOTS).is_ok());
+        let invalid = [
+            0,
+            MIN_QUEUE_ENTRY_AGE_SLOTS - 1,
+            MAX_QUEUE_ENTRY_AGE_SLOTS + 1,
+            u64::MAX,
+        ];
+        for max_entry_age_slots in invalid {
+            assert_eq!(
+                check_queue_entry_age(max_entry_age_slots).unwrap_err(),
+                error!(CustomError::InvalidQueueEntryAge)
+            );
+        }
+    }
+
+    #[test]
+    fn queue_entries_expire_after_their_max_age() {
+        let entry = GamePlayer {
+            address: Pubkey::new_unique(),
+            session_key: Pubkey::new_unique(),
+            session_scope: SessionScope {
+                expires_slot: u64::MAX,
+                permissions: SESSION_ALL,
+            },
+            board_root: [0; 32],
+            enqueued_slot: 1_000,
+            wager_mint: None,
+            settings: GameSettings {
+                wager_amount: 0,
+                turn_duration_slots: 75,
+                time_bank_slots: 0,
+                rule_set: classic(),
+            },
+        };
+        assert!(!is_queue_entry_expired(&entry, 150, 1_150));
+        assert!(is_queue_entry_expired(&entry, 150, 1_151));
+        assert!(!is_queue_entry_expired(&entry, u64::MAX, u64::MAX));
+    }
+
+    #[test]
+    fn alternating_turns_switch_the_attacker() {
+        let mut rule_set = classic();
+        rule_set.turn_mode = TurnMode::Alternating;
+        let mut game = game(rule_set);
+        let (player1, player2) = (game.player1, game.player2);
+        skip_idle_moves(&mut game);
+        assert!(owes_move(&game, player1) && !owes_move(&game, player2));
+
+        game.player1_attacked_this_turn = true;
+        game.player1_shots = vec![7];
+        game.status = GameStatus::AwaitingProofs;
+        assert!(!owes_move(&game, player1) && owes_move(&game, player2));
+
+        game.player2_verified_proof_this_turn = true;
+        start_next_turn(&mut game, 500);
+        assert_eq!(game.current_turn, 2);
+        assert_eq!(game.status, GameStatus::AwaitingAttacks);
+        assert_eq!(game.side_to_move, player2);
+        assert!(game.player1_shots.is_empty());
+        assert!(!owes_move(&game, player1) && owes_move(&game, player2));
+        assert_eq!(game.turn_start_slot, 500);
+        assert_eq!(game.player1_clock_started_slot, 500);
+        assert_eq!(game.player2_clock_started_slot, 500);
+
+        game.player2_attacked_this_turn = true;
+        game.status = GameStatus::AwaitingProofs;
+        assert!(owes_move(&game, player1) && !owes_move(&game, player2));
+    }
+
+    #[test]
+    fn simultaneous_turns_need_both_players() {
+        let mut game = game(classic());
+        let player1 = game.player1;
+        skip_idle_moves(&mut game);
+        assert!(!game.player2_attacked_this_turn && !game.player1_verified_proof_this_turn);
+
+        start_next_turn(&mut game, 500);
+        assert_eq!(game.side_to_move, player1);
+        assert!(owes_move(&game, player1) && owes_move(&game, game.player2));
+    }
+
+    #[test]
+    fn game_account_fits_the_cpi_allocation_limit() {
+        const { assert!(8 + Game::INIT_SPACE <= 10_240) };
+    }
+}
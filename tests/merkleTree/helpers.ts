@@ -1,71 +1,74 @@
-export function getFixedBoard1(): boolean[][] {
-    // 10x10 board (false represents water, true represents part of a ship)
-    const board = Array(10).fill(false).map(() => Array(10).fill(false));
+// Ship id of water fields, see `NO_SHIP` in the program
+export const NO_SHIP = 255;
+
+export function getFixedBoard1(): number[][] {
+    // 10x10 board (NO_SHIP represents water, anything else the id of the ship on the field)
+    const board = Array(10).fill(NO_SHIP).map(() => Array(10).fill(NO_SHIP));
 
     // Ship 1 (size 5): placed horizontally from (0,0) to (0,4)
     for (let i = 0; i < 5; i++) {
-        board[0][i] = true;
+        board[0][i] = 0;
     }
 
     // Ship 2 (size 4): placed vertically from (2,2) to (5,2)
     for (let i = 2; i < 6; i++) {
-        board[i][2] = true;
+        board[i][2] = 1;
     }
 
     // Ship 3 (size 3): placed horizontally from (7,5) to (7,7)
     for (let i = 5; i < 8; i++) {
-        board[7][i] = true;
+        board[7][i] = 2;
     }
 
-    // Ship 4 (size 3): placed vertically from (4,8) to (6,8)
-    for (let i = 4; i < 7; i++) {
-        board[i][8] = true;
+    // Ship 4 (size 3): placed vertically from (3,8) to (5,8)
+    for (let i = 3; i < 6; i++) {
+        board[i][8] = 3;
     }
 
-    // Ship 5 (size 2): placed horizontally from (9,5) to (9,8)
+    // Ship 5 (size 2): placed horizontally from (9,5) to (9,6)
     for (let i = 5; i < 7; i++) {
-        board[9][i] = true;
+        board[9][i] = 4;
     }
 
     return board;
 }
 
 // Another predefined board setup function for testing
-export function getFixedBoard2(): boolean[][] {
-    // 10x10 board (false represents water, true represents part of a ship)
-    const board = Array(10).fill(false).map(() => Array(10).fill(false));
+export function getFixedBoard2(): number[][] {
+    // 10x10 board (NO_SHIP represents water, anything else the id of the ship on the field)
+    const board = Array(10).fill(NO_SHIP).map(() => Array(10).fill(NO_SHIP));
 
     // Ship 1 (size 5): placed vertically from (0,1) to (4,1)
     for (let i = 0; i < 5; i++) {
-        board[i][1] = true;
+        board[i][1] = 0;
     }
 
     // Ship 2 (size 4): placed horizontally from (6,3) to (6,6)
     for (let i = 3; i < 7; i++) {
-        board[6][i] = true;
+        board[6][i] = 1;
     }
 
     // Ship 3 (size 3): placed vertically from (3,8) to (5,8)
     for (let i = 3; i < 6; i++) {
-        board[i][8] = true;
+        board[i][8] = 2;
     }
 
     // Ship 4 (size 3): placed horizontally from (8,0) to (8,2)
     for (let i = 0; i < 3; i++) {
-        board[8][i] = true;
+        board[8][i] = 3;
     }
 
-    // Ship 5 (size 2): placed vertically from (1,5) to (4,5)
+    // Ship 5 (size 2): placed vertically from (1,5) to (2,5)
     for (let i = 1; i < 3; i++) {
-        board[i][5] = true;
+        board[i][5] = 4;
     }
 
     return board;
 }
 
-export function printBoard(board: boolean[][]): void {
+export function printBoard(board: number[][]): void {
     for (let row of board) {
-        console.log(row.map(cell => (cell ? 'X' : 'O')).join(' '));
+        console.log(row.map(cell => (cell !== NO_SHIP ? cell.toString() : 'O')).join(' '));
     }
     console.log("\n");
 }
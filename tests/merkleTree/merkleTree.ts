@@ -2,6 +2,7 @@ import { createHash } from 'crypto-browserify';
 import { SeededRNG } from "./seededRng";
 import { blake3 } from "hash-wasm";
 import * as anchor from "@coral-xyz/anchor";
+import { NO_SHIP } from "./helpers";
 
 const rng = new SeededRNG(12345); // Example seed, ensure to use the same seed across both files for consistency
 
@@ -10,6 +11,7 @@ export type MerkleNode = {
     left?: MerkleNode;
     right?: MerkleNode;
     data?: boolean; // Assuming the cell state is a boolean
+    shipId?: number; // Ship the cell belongs to, NO_SHIP for water
     secret?: anchor.BN; // u64 secret for each cell
     fieldIndex?: number; // Index of the field in the board
 };
@@ -24,26 +26,17 @@ export type MerkleNode = {
 //     return hash.digest('hex');
 // }
 
-export async function constructMerkleTree(board: boolean[][]): Promise<[MerkleNode, number[][]]> {
-    let secrets: number[][] = Array.from({ length: 10 }, () => Array(10).fill(0));
+// Returns the root and the board seed every field secret is derived from
+export async function constructMerkleTree(board: number[][]): Promise<[MerkleNode, Uint8Array]> {
+    const seed = Uint8Array.from({ length: 32 }, () => Math.floor(rng.random() * 256));
     let nodes: MerkleNode[] = await Promise.all(board.flat().map(async (cell, index) => {
-        // Generate or assign a unique u64 secret for each cell
-        const secret = new anchor.BN(Math.floor(rng.random() * Number.MAX_SAFE_INTEGER));
-        const buffer = new Uint8Array(2); // 1 byte for index, 1 byte for shipPlaced, 8 bytes for secret
-        buffer[0] = index; // First byte for index (0-255)
-        buffer[1] = cell ? 1 : 0; // Second byte for shipPlaced (boolean to 0 or 1)
-        // const secretBytes = new DataView(new ArrayBuffer(8));
-        // secretBytes.setBigUint64(0, BigInt(secret.toString()), true); // true for little-endian
-
-        // buffer.set(new Uint8Array(secretBytes.buffer), 2); // Set the secret bytes starting at index 2
+        const secret = await fieldSecret(seed, index);
+        const buffer = serializeLeaf(index, cell !== NO_SHIP, cell, secret);
         const h = await blake3(buffer);
         console.log("Buffer for index", index, "and cell", cell, ":", buffer);
         console.log("Hash: ", h)
 
-        const rowIndex = Math.floor(index / 10);
-        const colIndex = index % 10;
-        secrets[rowIndex][colIndex] = Number(secret);
-        return { hash: h, data: cell, secret: secret, fieldIndex: index };
+        return { hash: h, data: cell !== NO_SHIP, shipId: cell, secret: secret, fieldIndex: index };
     }));
 
     // Calculate the next power of 2 greater than or equal to the length of nodes
@@ -51,10 +44,9 @@ export async function constructMerkleTree(board: boolean[][]): Promise<[MerkleNo
 
     // Add default nodes to make the total count a power of 2
     while (nodes.length < nextPowerOf2) {
-        const buffer = new Uint8Array(2);
-        buffer[0] = nodes.length
-        buffer[1] = 0;
-        nodes.push({ hash: await blake3(buffer), data: undefined, secret: new anchor.BN(0), fieldIndex: nodes.length });
+        // Canonical padding leaf, see `GameField::padding` in the program
+        const buffer = serializeLeaf(nodes.length, false, NO_SHIP, new anchor.BN(0));
+        nodes.push({ hash: await blake3(buffer), data: undefined, shipId: NO_SHIP, secret: new anchor.BN(0), fieldIndex: nodes.length });
     }
 
     while (nodes.length > 1) {
@@ -72,7 +64,43 @@ export async function constructMerkleTree(board: boolean[][]): Promise<[MerkleNo
         }
         nodes = parentNodes;
     }
-    return [nodes[0], secrets]; // Root node and secrets array
+    return [nodes[0], seed];
+}
+
+// Mirrors `field_secret` in the program: the first 8 bytes of blake3(seed || index), little endian
+export async function fieldSecret(seed: Uint8Array, index: number): Promise<anchor.BN> {
+    const preimage = new Uint8Array(33);
+    preimage.set(seed);
+    preimage[32] = index;
+    const digest = await blake3(preimage);
+    return new anchor.BN(Buffer.from(digest.slice(0, 16), "hex"), "le");
+}
+
+// Sibling hashes from the leaf at `index` up to the root, the order `verify_merkle_proof` expects
+export function getMerkleProof(root: MerkleNode, index: number): string[] {
+    let depth = 0;
+    for (let node = root; node.left; node = node.left) {
+        depth++;
+    }
+
+    const siblings: string[] = [];
+    let node = root;
+    for (let level = depth - 1; level >= 0; level--) {
+        const goRight = ((index >> level) & 1) === 1;
+        siblings.push(goRight ? node.left.hash : node.right.hash);
+        node = goRight ? node.right : node.left;
+    }
+    return siblings.reverse();
+}
+
+// Mirrors `GameField::serialize` in the program
+export function serializeLeaf(index: number, shipPlaced: boolean, shipId: number, secret: anchor.BN): Uint8Array {
+    const buffer = new Uint8Array(11); // 1 byte for index, 1 byte for shipPlaced, 1 byte for shipId, 8 bytes for secret
+    buffer[0] = index; // First byte for index (0-255)
+    buffer[1] = shipPlaced ? 1 : 0; // Second byte for shipPlaced (boolean to 0 or 1)
+    buffer[2] = shipId; // Third byte for the ship id (NO_SHIP for water)
+    buffer.set(secret.toArrayLike(Buffer, "le", 8), 3); // Set the secret bytes starting at index 3
+    return buffer;
 }
 
 function hexToUint8Array(hex) {
@@ -1,11 +1,35 @@
 import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
+import { expect } from "chai";
 import { Solship } from "../target/types/solship";
 import { PublicKey, LAMPORTS_PER_SOL, Keypair, ComputeBudgetInstruction, ComputeBudgetProgram } from "@solana/web3.js";
-import { constructMerkleTree } from "./merkleTree/merkleTree";
-import { getFixedBoard1, getFixedBoard2, hexStringToByteArray, printBoard } from "./merkleTree/helpers";
+import { createAssociatedTokenAccount, createMint, getAccount, getAssociatedTokenAddressSync, getOrCreateAssociatedTokenAccount, mintTo, transfer, ASSOCIATED_TOKEN_PROGRAM_ID, TOKEN_PROGRAM_ID } from "@solana/spl-token";
+import { constructMerkleTree, fieldSecret, getMerkleProof, MerkleNode } from "./merkleTree/merkleTree";
+import { getFixedBoard1, getFixedBoard2, hexStringToByteArray, NO_SHIP, printBoard } from "./merkleTree/helpers";
 
 const INITIAL_BALANCE = 1000; // 1000 SOL
+const MAX_QUEUE_ENTRY_AGE_SLOTS = 9000; // ~1 hour
+const QUEUE_PAGE = 0;
+const WAGER_LAMPORTS = LAMPORTS_PER_SOL;
+const WAGER_TOKENS = 1000;
+const FEE_BPS = 250; // 2.5%
+const MIN_TURN_DURATION_SLOTS = 25; // 10s
+const MAX_TURN_DURATION_SLOTS = 216000; // ~1 day
+const TURN_DURATION_SLOTS = 75; // 30s
+const TIME_BANK_SLOTS = 0; // No chess clock
+const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111");
+// Classic 10x10 board with a carrier, a battleship, two cruisers and a destroyer, none of them touching
+const CLASSIC_RULE_SET = { width: 10, height: 10, fleet: Buffer.from([5, 4, 3, 3, 2]), adjacency: { classic: {} }, shotsPerTurn: { fixed: { shots: 1 } }, turnMode: { simultaneous: {} } };
+// Smallest game that still takes a few turns: a 4x4 board with a single destroyer
+const QUICK_RULE_SET = { width: 4, height: 4, fleet: Buffer.from([2]), adjacency: { mayTouch: {} }, shotsPerTurn: { fixed: { shots: 1 } }, turnMode: { simultaneous: {} } };
+const GAME_SETTINGS = {
+	wagerAmount: new anchor.BN(WAGER_LAMPORTS),
+	turnDurationSlots: new anchor.BN(TURN_DURATION_SLOTS),
+	timeBankSlots: new anchor.BN(TIME_BANK_SLOTS),
+	ruleSet: CLASSIC_RULE_SET,
+};
+// Session keys may attack, prove, claim and resign (see `SESSION_*` in the program) until the last slot
+const SESSION_SCOPE = { expiresSlot: new anchor.BN("18446744073709551615"), permissions: 0b1111 };
 
 describe("solship", () => {
 	// Configure the client to use the local cluster.
@@ -17,7 +41,13 @@ describe("solship", () => {
 		await airdropLamports("TN9afBn533hvXpQ1s5uexBUksR7yMUMjcfgLLc1QKrz", INITIAL_BALANCE * LAMPORTS_PER_SOL);
 		await airdropLamports("4zvwRjXUKGfvwnParsHAS3HuSVzV5cA4McphgmoCtajS", INITIAL_BALANCE * LAMPORTS_PER_SOL);
 
-		const tx = await program.methods.initializeQueue().rpc();
+		const tx = await program.methods.initializeQueue(QUEUE_PAGE, new anchor.BN(MAX_QUEUE_ENTRY_AGE_SLOTS)).rpc();
+		await program.methods.initializeGameCounter().rpc();
+		// The provider wallet deployed the program, so it is the upgrade authority, the admin and the treasury
+		const [programData] = PublicKey.findProgramAddressSync([program.programId.toBuffer()], BPF_LOADER_UPGRADEABLE_PROGRAM_ID);
+		await program.methods.initializeConfig(FEE_BPS, anchor.getProvider().publicKey, new anchor.BN(MIN_TURN_DURATION_SLOTS), new anchor.BN(MAX_TURN_DURATION_SLOTS))
+			.accounts({ programData })
+			.rpc();
 
 		console.log("Transaction signature: ", tx);
 	});
@@ -32,23 +62,25 @@ describe("solship", () => {
 
 		const player1Board = getFixedBoard1();
 		printBoard(player1Board);
-		const [player1MerkleRoot, secrets1] = await constructMerkleTree(player1Board);
+		const [player1MerkleRoot, seed1] = await constructMerkleTree(player1Board);
 		console.log("Player 1 Merkle root:", player1MerkleRoot.hash);
 		const player2Board = getFixedBoard2();
 		printBoard(player2Board);
-		const [player2MerkleRoot, secrets2] = await constructMerkleTree(player2Board);
+		const [player2MerkleRoot, seed2] = await constructMerkleTree(player2Board);
 		console.log("Player 2 Merkle root:", player2MerkleRoot.hash);
 
-		const tx1 = await program.methods.joinQueue(hexStringToByteArray(player1MerkleRoot.hash))
+		const tx1 = await program.methods.joinQueue(QUEUE_PAGE, hexStringToByteArray(player1MerkleRoot.hash), GAME_SETTINGS, SESSION_SCOPE)
 			.accounts({
 				player: player1.publicKey,
+				sessionKey: player1.publicKey,
 			})
 			.signers([player1])
 			.rpc();
 
-		const tx2 = await program.methods.createGame(player1.publicKey, hexStringToByteArray(player2MerkleRoot.hash))
+		const tx2 = await program.methods.createGame(QUEUE_PAGE, player1.publicKey, hexStringToByteArray(player2MerkleRoot.hash), GAME_SETTINGS, SESSION_SCOPE)
 			.accounts({
 				player: player2.publicKey,
+				sessionKey: player2.publicKey,
 			})
 			.signers([player2])
 			.rpc();
@@ -56,49 +88,307 @@ describe("solship", () => {
 		console.log("GAME: ", await program.account.game.all());
 
 		const gameAddr = (await program.account.game.all())[0].publicKey;
+		const [vaultAddr] = PublicKey.findProgramAddressSync([Buffer.from("vault"), gameAddr.toBuffer()], program.programId);
 
-		// Create an array with 28 padding leaves
-		const paddingLeaves = Array(28).fill(null).map((_, index) => {
-			const ind = index + 100;
-			const row = Math.floor(ind / 10);
-			const col = ind % 10;
-
-			// console.log(`Index: ${ind}, Row: ${row}, Col: ${col}`);
-
-			return {
-			  shipPlaced: false,
-			//   secret: new anchor.BN(0),
-			};
-		  });
-
-		const player1ClaimWinBoard = player1Board.flat().map((cell, index) => {
-			const row = Math.floor(index / 10);
-			const col = index % 10;
-			return ({ shipPlaced: cell });
-		}).concat(paddingLeaves);
-
-		try {
-			const tx3 = await program.methods.claimWin(player1ClaimWinBoard)
-				.accountsStrict({
-					game: gameAddr,
-					player: player1.publicKey,
-				})
-				.preInstructions([
-					ComputeBudgetProgram.setComputeUnitLimit({
-						units: 1_400_000
-					})
-				])
-				.signers([player1])
-				.rpc({
-					// skipPreflight: true,
+		// Every field secret is derived from the seed, so the table is one ship id per field
+		const player1ClaimWinBoard = { seed: Array.from(seed1), shipIds: Buffer.from(player1Board.flat()) };
+
+		// Nobody has missed a deadline yet
+		const err = await program.methods.claimWin(player1ClaimWinBoard)
+			.accountsStrict({
+				game: gameAddr,
+				player: player1.publicKey,
+				vault: vaultAddr,
+				treasury: anchor.getProvider().publicKey,
+				// player2 created the game, so it is the game's player1
+				player1: player2.publicKey,
+				player2: player1.publicKey,
+				// The wager is in SOL, so no token accounts are needed
+				gameTokenVault: null,
+				player1TokenAccount: null,
+				player2TokenAccount: null,
+				treasuryTokenAccount: null,
+				tokenProgram: null,
+			})
+			.preInstructions([
+				ComputeBudgetProgram.setComputeUnitLimit({
+					units: 1_400_000
 				})
-		}
-		catch (err) {
-			console.log(err);
-		}
+			])
+			.signers([player1])
+			.rpc({
+				// skipPreflight: true,
+			})
+			.then(() => null, (err) => err);
+		expect(err).to.be.instanceOf(anchor.AnchorError);
+		expect(err.error.errorCode.code).to.equal("TurnNotExpired");
+	});
+
+	it("Plays a staked game to the end and pays the winner", async () => {
+		const provider = anchor.getProvider();
+		const settings = { ...GAME_SETTINGS, ruleSet: QUICK_RULE_SET };
+		// Alice joins the queue and Bob matches her, so Bob is the game's player1
+		const alice = Keypair.generate();
+		const bob = Keypair.generate();
+		await airdropLamports(alice.publicKey.toBase58(), INITIAL_BALANCE * LAMPORTS_PER_SOL);
+		await airdropLamports(bob.publicKey.toBase58(), INITIAL_BALANCE * LAMPORTS_PER_SOL);
+
+		// Alice's destroyer is on fields 0 and 1, Bob's on fields 14 and 15
+		const aliceBoard = quickBoard([0, 1]);
+		const bobBoard = quickBoard([14, 15]);
+		const [aliceRoot, aliceSeed] = await constructMerkleTree(aliceBoard);
+		const [bobRoot, bobSeed] = await constructMerkleTree(bobBoard);
+
+		await program.methods.joinQueue(QUEUE_PAGE, hexStringToByteArray(aliceRoot.hash), settings, SESSION_SCOPE)
+			.accounts({ player: alice.publicKey, sessionKey: alice.publicKey })
+			.signers([alice])
+			.rpc();
+
+		const [gameCounterAddr] = PublicKey.findProgramAddressSync([Buffer.from("game_counter")], program.programId);
+		const gameId = (await program.account.gameCounter.fetch(gameCounterAddr)).nextGameId;
+		const [game] = PublicKey.findProgramAddressSync([Buffer.from("game"), gameId.toArrayLike(Buffer, "le", 8)], program.programId);
+		const [vault] = PublicKey.findProgramAddressSync([Buffer.from("vault"), game.toBuffer()], program.programId);
+
+		await program.methods.createGame(QUEUE_PAGE, alice.publicKey, hexStringToByteArray(bobRoot.hash), settings, SESSION_SCOPE)
+			.accounts({ player: bob.publicKey, sessionKey: bob.publicKey })
+			.signers([bob])
+			.rpc();
+		expect(await provider.connection.getBalance(vault)).to.be.greaterThanOrEqual(2 * WAGER_LAMPORTS);
+
+		const playTurn = async (aliceTarget: number, bobTarget: number) => {
+			await program.methods.attack(Buffer.from([aliceTarget])).accounts({ player: alice.publicKey, game }).signers([alice]).rpc();
+			await program.methods.attack(Buffer.from([bobTarget])).accounts({ player: bob.publicKey, game }).signers([bob]).rpc();
+			const bobAnswer = await program.methods.verifyProof([await fieldProof(bobRoot, bobSeed, bobBoard, aliceTarget)])
+				.accounts({ player: bob.publicKey, game })
+				.signers([bob])
+				.rpc({ commitment: "confirmed" });
+			await program.methods.verifyProof([await fieldProof(aliceRoot, aliceSeed, aliceBoard, bobTarget)])
+				.accounts({ player: alice.publicKey, game })
+				.signers([alice])
+				.rpc();
+			return bobAnswer;
+		};
+
+		// Alice hits Bob's destroyer twice while Bob misses
+		const firstHit = await playTurn(14, 5);
+		expect(findEvent(await eventsOf(firstHit), "ShipSunk")).to.be.undefined;
+		const sinkingHit = await playTurn(15, 6);
+		const shipSunk = findEvent(await eventsOf(sinkingHit), "ShipSunk");
+		expect(shipSunk.data.player.equals(bob.publicKey)).to.be.true;
+		expect(shipSunk.data.length).to.equal(2);
+
+		const settlementAccounts = {
+			game,
+			vault,
+			treasury: provider.publicKey,
+			player1: bob.publicKey,
+			player2: alice.publicKey,
+			gameTokenVault: null,
+			player1TokenAccount: null,
+			player2TokenAccount: null,
+			treasuryTokenAccount: null,
+			tokenProgram: null,
+		};
+
+		const claim = await program.methods.claimWin({ seed: Array.from(aliceSeed), shipIds: Buffer.from(aliceBoard.flat()) })
+			.accountsStrict({ ...settlementAccounts, player: alice.publicKey })
+			.signers([alice])
+			.rpc({ commitment: "confirmed" });
+		const gameFinished = findEvent(await eventsOf(claim), "GameFinished");
+		expect(gameFinished.data.winner.equals(alice.publicKey)).to.be.true;
+		expect(gameFinished.data.reason).to.deep.equal({ fleetSunk: {} });
+
+		// The pot stays in the vault until the loser has revealed their board
+		const aliceBefore = await provider.connection.getBalance(alice.publicKey);
+		const treasuryBefore = await provider.connection.getBalance(provider.publicKey);
+		const vaultBefore = await provider.connection.getBalance(vault);
+
+		const reveal = await program.methods.revealBoard({ seed: Array.from(bobSeed), shipIds: Buffer.from(bobBoard.flat()) })
+			.accountsStrict({ ...settlementAccounts, player: bob.publicKey })
+			.signers([bob])
+			.rpc({ commitment: "confirmed" });
+		expect(findEvent(await eventsOf(reveal), "BoardRevealed").data.player.equals(bob.publicKey)).to.be.true;
+
+		const pot = 2 * WAGER_LAMPORTS;
+		const fee = pot * FEE_BPS / 10_000;
+		// The provider wallet pays the transaction fee and is also the treasury
+		const revealTx = await provider.connection.getTransaction(reveal, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+		expect(await provider.connection.getBalance(alice.publicKey) - aliceBefore).to.equal(pot - fee);
+		expect(await provider.connection.getBalance(provider.publicKey) - treasuryBefore).to.equal(fee - revealTx.meta.fee);
+		expect(vaultBefore - await provider.connection.getBalance(vault)).to.equal(pot);
+
+		const finished = await program.account.game.fetch(game);
+		expect(finished.status).to.deep.equal({ finished: {} });
+		expect(finished.loserRevealedBoard).to.be.true;
+	});
+
+	it("Pays a token wager and sweeps dust out of the vault before closing the game", async () => {
+		const provider = anchor.getProvider();
+		const settings = { ...GAME_SETTINGS, wagerAmount: new anchor.BN(WAGER_TOKENS), ruleSet: QUICK_RULE_SET };
+		// Alice joins the queue and Bob matches her, so Bob is the game's player1
+		const alice = Keypair.generate();
+		const bob = Keypair.generate();
+		await airdropLamports(alice.publicKey.toBase58(), INITIAL_BALANCE * LAMPORTS_PER_SOL);
+		await airdropLamports(bob.publicKey.toBase58(), INITIAL_BALANCE * LAMPORTS_PER_SOL);
+
+		// Alice mints the wager token, both players start with enough for two stakes
+		const wagerMint = await createMint(provider.connection, alice, alice.publicKey, null, 0);
+		const aliceTokens = await createAssociatedTokenAccount(provider.connection, alice, wagerMint, alice.publicKey);
+		const bobTokens = await createAssociatedTokenAccount(provider.connection, bob, wagerMint, bob.publicKey);
+		const treasuryTokens = (await getOrCreateAssociatedTokenAccount(provider.connection, alice, wagerMint, provider.publicKey)).address;
+		await mintTo(provider.connection, alice, wagerMint, aliceTokens, alice, 2 * WAGER_TOKENS);
+		await mintTo(provider.connection, alice, wagerMint, bobTokens, alice, 2 * WAGER_TOKENS);
+
+		const aliceBoard = quickBoard([0, 1]);
+		const bobBoard = quickBoard([14, 15]);
+		const [aliceRoot, aliceSeed] = await constructMerkleTree(aliceBoard);
+		const [bobRoot] = await constructMerkleTree(bobBoard);
+
+		const [aliceTicket] = PublicKey.findProgramAddressSync([Buffer.from("queue_ticket"), alice.publicKey.toBuffer()], program.programId);
+		const aliceTicketTokens = getAssociatedTokenAddressSync(wagerMint, aliceTicket, true);
+		const tokenPrograms = { tokenProgram: TOKEN_PROGRAM_ID, associatedTokenProgram: ASSOCIATED_TOKEN_PROGRAM_ID };
+		await program.methods.joinQueue(QUEUE_PAGE, hexStringToByteArray(aliceRoot.hash), settings, SESSION_SCOPE)
+			.accountsPartial({
+				player: alice.publicKey,
+				sessionKey: alice.publicKey,
+				wagerMint,
+				playerTokenAccount: aliceTokens,
+				ticketTokenAccount: aliceTicketTokens,
+				...tokenPrograms,
+			})
+			.signers([alice])
+			.rpc();
+
+		const [gameCounterAddr] = PublicKey.findProgramAddressSync([Buffer.from("game_counter")], program.programId);
+		const gameId = (await program.account.gameCounter.fetch(gameCounterAddr)).nextGameId;
+		const [game] = PublicKey.findProgramAddressSync([Buffer.from("game"), gameId.toArrayLike(Buffer, "le", 8)], program.programId);
+		const [vault] = PublicKey.findProgramAddressSync([Buffer.from("vault"), game.toBuffer()], program.programId);
+		const gameTokenVault = getAssociatedTokenAddressSync(wagerMint, game, true);
+
+		await program.methods.createGame(QUEUE_PAGE, alice.publicKey, hexStringToByteArray(bobRoot.hash), settings, SESSION_SCOPE)
+			.accountsPartial({
+				player: bob.publicKey,
+				sessionKey: bob.publicKey,
+				wagerMint,
+				playerTokenAccount: bobTokens,
+				enemyTicketTokenAccount: aliceTicketTokens,
+				gameTokenVault,
+				...tokenPrograms,
+			})
+			.signers([bob])
+			.rpc();
+		expect(Number((await getAccount(provider.connection, gameTokenVault)).amount)).to.equal(2 * WAGER_TOKENS);
+
+		const settlementAccounts = {
+			game,
+			vault,
+			treasury: provider.publicKey,
+			player1: bob.publicKey,
+			player2: alice.publicKey,
+			gameTokenVault,
+			player1TokenAccount: bobTokens,
+			player2TokenAccount: aliceTokens,
+			treasuryTokenAccount: treasuryTokens,
+			tokenProgram: TOKEN_PROGRAM_ID,
+		};
+
+		// Alice gives up right away and proves her board, which pays Bob the pot minus the fee
+		await program.methods.resign()
+			.accountsStrict({ ...settlementAccounts, player: alice.publicKey })
+			.signers([alice])
+			.rpc();
+		await program.methods.revealBoard({ seed: Array.from(aliceSeed), shipIds: Buffer.from(aliceBoard.flat()) })
+			.accountsStrict({ ...settlementAccounts, player: alice.publicKey })
+			.signers([alice])
+			.rpc();
+
+		const pot = 2 * WAGER_TOKENS;
+		const fee = pot * FEE_BPS / 10_000;
+		expect(Number((await getAccount(provider.connection, bobTokens)).amount)).to.equal(WAGER_TOKENS + pot - fee);
+		expect(Number((await getAccount(provider.connection, treasuryTokens)).amount)).to.equal(fee);
+		expect(Number((await getAccount(provider.connection, gameTokenVault)).amount)).to.equal(0);
+
+		// Tokens sent to the settled game must not keep its vault from being closed
+		const dust = 7;
+		await transfer(provider.connection, alice, aliceTokens, gameTokenVault, alice, dust);
+
+		await program.methods.closeGame()
+			.accountsStrict({ game, vault, player1: bob.publicKey, gameTokenVault, player1TokenAccount: bobTokens, tokenProgram: TOKEN_PROGRAM_ID })
+			.rpc();
+
+		expect(Number((await getAccount(provider.connection, bobTokens)).amount)).to.equal(WAGER_TOKENS + pot - fee + dust);
+		expect(await provider.connection.getAccountInfo(gameTokenVault)).to.be.null;
+		expect(await provider.connection.getAccountInfo(game)).to.be.null;
+	});
+
+	it("Keeps fresh queue entries and refunds players leaving from their page", async () => {
+		const provider = anchor.getProvider();
+		const otherPage = QUEUE_PAGE + 1;
+		await program.methods.initializeQueue(otherPage, new anchor.BN(MAX_QUEUE_ENTRY_AGE_SLOTS)).rpc();
+
+		const alice = Keypair.generate();
+		await airdropLamports(alice.publicKey.toBase58(), INITIAL_BALANCE * LAMPORTS_PER_SOL);
+		const [aliceRoot] = await constructMerkleTree(getFixedBoard1());
+		await program.methods.joinQueue(QUEUE_PAGE, hexStringToByteArray(aliceRoot.hash), GAME_SETTINGS, SESSION_SCOPE)
+			.accounts({ player: alice.publicKey, sessionKey: alice.publicKey })
+			.signers([alice])
+			.rpc();
+
+		const [queue] = PublicKey.findProgramAddressSync([Buffer.from("queue"), new anchor.BN(QUEUE_PAGE).toArrayLike(Buffer, "le", 2)], program.programId);
+		const inQueue = async () => (await program.account.queue.fetch(queue)).players.some(p => p.address.equals(alice.publicKey));
+
+		// Alice's entry is far from its max age, pruning leaves it alone
+		await program.methods.pruneQueue(QUEUE_PAGE).rpc();
+		expect(await inQueue()).to.be.true;
+
+		const err = await program.methods.leaveQueue(otherPage)
+			.accounts({ player: alice.publicKey })
+			.signers([alice])
+			.rpc()
+			.then(() => null, (err) => err);
+		expect(err).to.be.instanceOf(anchor.AnchorError);
+		expect(err.error.errorCode.code).to.equal("WrongQueuePage");
+
+		// The provider wallet pays the transaction fee, so Alice gets exactly her stake back
+		const aliceBefore = await provider.connection.getBalance(alice.publicKey);
+		await program.methods.leaveQueue(QUEUE_PAGE)
+			.accounts({ player: alice.publicKey })
+			.signers([alice])
+			.rpc();
+		expect(await provider.connection.getBalance(alice.publicKey) - aliceBefore).to.equal(WAGER_LAMPORTS);
+		expect(await inQueue()).to.be.false;
 	});
 });
 
+// 4x4 board of `QUICK_RULE_SET` with ship 0 on `fields`
+function quickBoard(fields: number[]): number[][] {
+	const board = Array(4).fill(NO_SHIP).map(() => Array(4).fill(NO_SHIP));
+	for (const field of fields) {
+		board[Math.floor(field / 4)][field % 4] = 0;
+	}
+	return board;
+}
+
+async function fieldProof(root: MerkleNode, seed: Uint8Array, board: number[][], index: number) {
+	const shipId = board.flat()[index];
+	return {
+		proof: getMerkleProof(root, index).map(hexStringToByteArray),
+		leaf: { index, shipPlaced: shipId !== NO_SHIP, shipId, secret: await fieldSecret(seed, index) },
+	};
+}
+
+async function eventsOf(signature: string) {
+	const program = anchor.workspace.Solship as Program<Solship>;
+	const tx = await anchor.getProvider().connection.getTransaction(signature, { commitment: "confirmed", maxSupportedTransactionVersion: 0 });
+	const parser = new anchor.EventParser(program.programId, new anchor.BorshCoder(program.idl));
+	return Array.from(parser.parseLogs(tx.meta.logMessages));
+}
+
+// Event names are camel cased in the generated IDL
+function findEvent(events: anchor.Event[], name: string): anchor.Event | undefined {
+	return events.find(event => event.name.toLowerCase() === name.toLowerCase());
+}
+
 async function airdropLamports(recipient: string, amount: number) {
 	const signature = await anchor.getProvider().connection.requestAirdrop(new PublicKey(recipient), amount);
 